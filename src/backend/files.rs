@@ -0,0 +1,1483 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::fs::{
+    self as unix_fs, DirBuilderExt, MetadataExt, OpenOptionsExt, PermissionsExt,
+};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::{fmt, process, thread, time::Duration, time::Instant};
+
+use super::{create_archive_dir, operation_error, Backend};
+use crate::{consts, date, s, ssh, sudo};
+use crate::{
+    differ::{GroupChange, SudoersChange, UserChange},
+    filter::{IdFilter, LoginDefs},
+    sync_structs::{AccountState, Group, HomePolicy, HomeRemoval, User},
+    system_parser::{self, AgingField, RawEntry, SystemGroup, SystemUser},
+};
+
+/// The time to wait for the lock of the user database, like `lckpwdf()`.
+const LOCK_TIMEOUT: Duration = Duration::from_secs(15);
+/// The mode of the new home directories, when the home policy doesn't set it.
+const HOME_MODE: u32 = 0o700;
+
+/**
+Backend editing the user database files (`/etc/passwd`, `/etc/group`, `/etc/shadow` and `/etc/gshadow`) directly.
+
+No external command is needed, so it also works on minimal images without shadow-utils.
+The files are locked for the whole run like the shadow-utils commands lock them,
+they are replaced with an atomic rename and the original files are kept as backups (`/etc/passwd-`, ...).
+ */
+#[derive(Debug)]
+pub struct Files {
+    /// When `true`, the modified files are printed instead of being written.
+    pub dry_run: bool,
+    /// When `true`, the modified files aren't printed in dry-run.
+    pub quiet: bool,
+    /// The root of the filesystem to modify (`/` for the live system).
+    pub root: PathBuf,
+    /// The filter selecting the ids allocated to the new users and groups.
+    filter: IdFilter,
+    /// The user database, read on the first operation.
+    database: Option<Database>,
+    /// The lock of the user database, held until the backend is dropped.
+    lock: Option<Lock>,
+}
+
+impl Files {
+    /**
+    Create a new files backend.
+
+    # Parameters
+    * `dry_run`: When `true`, the modified files are printed instead of being written.
+    * `quiet`: When `true`, nothing is printed.
+    * `root`: The root of the filesystem to modify (`/` for the live system).
+    * `filter`: The filter selecting the ids allocated to the new users and groups.
+     */
+    pub fn new(dry_run: bool, quiet: bool, root: &Path, filter: &IdFilter) -> Self {
+        Self {
+            dry_run,
+            quiet,
+            root: root.to_path_buf(),
+            filter: filter.clone(),
+            database: None,
+            lock: None,
+        }
+    }
+
+    /**
+    Apply an operation to the user database and write the modified files.
+    The database is locked and read on the first operation.
+
+    # Parameters
+    * `operation`: The operation, a failed operation leaves the database untouched.
+
+    # Returns
+    The result of the operation, or an error when the database couldn't be read or written.
+     */
+    fn apply<T>(
+        &mut self,
+        operation: impl FnOnce(&mut Database) -> io::Result<T>,
+    ) -> io::Result<T> {
+        let database = match &mut self.database {
+            Some(database) => database,
+            database @ None => {
+                if !self.dry_run {
+                    self.lock = Some(Lock::acquire(&self.root)?);
+                }
+                database.insert(Database::read(&self.root, &self.filter)?)
+            }
+        };
+
+        let mut copy = database.clone();
+        let rst = operation(&mut copy)?;
+        *database = copy;
+
+        if self.dry_run {
+            if !self.quiet {
+                println!("Skipped (dry-run)");
+            }
+            for table in database.tables_mut() {
+                if table.modified && !self.quiet {
+                    println!("\tedit {}", table.path.display());
+                }
+                table.modified = false;
+            }
+            return Ok(rst);
+        }
+
+        // The tables which couldn't be written are still modified and retried on the next operation.
+        for table in database.tables_mut() {
+            table.write()?;
+        }
+
+        Ok(rst)
+    }
+
+    /**
+    Apply an action to a home directory.
+
+    # Parameters
+    * `action`: The action, printed instead of being applied in dry-run.
+     */
+    fn apply_home(&self, action: HomeAction) -> io::Result<()> {
+        if self.dry_run {
+            if !self.quiet {
+                println!("\t{}", action);
+            }
+            return Ok(());
+        }
+
+        let path = |home: &str| system_parser::system_path(&self.root, home);
+        match action {
+            HomeAction::Create {
+                home,
+                uid,
+                gid,
+                skel,
+                mode,
+            } => {
+                let home = path(&home);
+                // Like `useradd`, an existing directory is left untouched.
+                if home.exists() {
+                    return Ok(());
+                }
+
+                if let Some(parent) = home.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+                fs::DirBuilder::new().mode(0o700).create(&home)?;
+                // The mode is set once created, so it isn't masked by the umask.
+                fs::set_permissions(&home, fs::Permissions::from_mode(mode))?;
+                unix_fs::chown(&home, Some(uid), Some(gid))?;
+                let skel = path(&skel);
+                if skel.is_dir() {
+                    copy_tree(&skel, &home, uid, gid)?;
+                }
+            }
+            HomeAction::Move { from, to } => {
+                let (from, to) = (path(&from), path(&to));
+                if from.is_dir() {
+                    fs::rename(&from, &to).map_err(|e| {
+                        operation_error(format!(
+                            "can't move {} to {}: {}",
+                            from.display(),
+                            to.display(),
+                            e
+                        ))
+                    })?;
+                }
+            }
+            HomeAction::Chown { home, uid, gid } => {
+                let home = path(&home);
+                if home.is_dir() {
+                    chown_tree(&home, uid, gid)?;
+                }
+            }
+            HomeAction::Remove { home, archive } => {
+                let home = path(&home);
+                // Like `userdel --remove`, a missing directory is skipped and the root is never removed.
+                if !home.is_dir() || home == path("/") {
+                    return Ok(());
+                }
+
+                if let Some(archive) = archive {
+                    archive_tree(&home, &path(&archive))?;
+                }
+                fs::remove_dir_all(&home)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Backend for Files {
+    fn dry_run(&self) -> bool {
+        self.dry_run
+    }
+
+    fn quiet(&self) -> bool {
+        self.quiet
+    }
+
+    fn add_group(&mut self, group: &Group) -> io::Result<()> {
+        self.apply(|db| {
+            // Like `groupadd --force`, an existing group is a success.
+            if db.group.get(&group.name).is_some() {
+                return Ok(());
+            }
+
+            let gid = match group.gid {
+                Some(gid) => gid,
+                None => db.next_gid()?,
+            };
+            // The synchronized users are added with `add_user`.
+            let members = group.members.clone().unwrap_or_default();
+            for member in &members {
+                db.passwd.field(member, consts::NAME_FIELD)?;
+            }
+            let members = members.join(",");
+            db.group
+                .insert(&[&group.name, "x", &gid.to_string(), &members]);
+            if let Some(gshadow) = &mut db.gshadow {
+                gshadow.insert(&[&group.name, "!", "", &members]);
+            }
+
+            Ok(())
+        })
+    }
+
+    fn modify_group(&mut self, group: &Group, change: &GroupChange) -> io::Result<()> {
+        self.apply(|db| match change {
+            GroupChange::Gid { to, .. } => {
+                let from = db.group.id(&group.name, consts::GID_FIELD)?;
+                db.group
+                    .set(&group.name, consts::GID_FIELD, to.to_string())?;
+
+                // The primary group of the users follows the group, like `groupmod`.
+                for username in db.passwd.names() {
+                    if db.passwd.id(&username, consts::GID_FIELD).ok() == Some(from) {
+                        db.passwd
+                            .set(&username, consts::GID_FIELD, to.to_string())?;
+                    }
+                }
+
+                Ok(())
+            }
+            GroupChange::Members { add, remove } => {
+                for member in add {
+                    db.passwd.field(member, consts::NAME_FIELD)?;
+                }
+
+                let mut tables = vec![&mut db.group];
+                tables.extend(db.gshadow.as_mut().filter(|t| t.get(&group.name).is_some()));
+                for table in tables {
+                    let mut members = table.list(&group.name, consts::USER_LIST_FIELD)?;
+                    members.retain(|m| !remove.contains(m));
+                    members.extend(add.iter().cloned());
+                    table.set(&group.name, consts::USER_LIST_FIELD, members.join(","))?;
+                }
+
+                Ok(())
+            }
+        })
+    }
+
+    fn delete_group(&mut self, group: &SystemGroup) -> io::Result<()> {
+        self.apply(|db| {
+            db.group.remove(&group.name)?;
+            if let Some(gshadow) = &mut db.gshadow {
+                gshadow.remove(&group.name).ok();
+            }
+
+            Ok(())
+        })
+    }
+
+    fn add_user(&mut self, user: &User, policy: &HomePolicy) -> io::Result<()> {
+        let home = user
+            .home
+            .clone()
+            .unwrap_or_else(|| format!("{}/{}", consts::HOME_BASE_DIR, user.username));
+
+        let (uid, gid) = self.apply(|db| {
+            if db.passwd.get(&user.username).is_some() {
+                return Err(operation_error(format!(
+                    "user '{}' already exists",
+                    user.username
+                )));
+            }
+
+            let gid = db.group.id(&user.groups[0], consts::GID_FIELD)?;
+            let uid = match user.uid {
+                Some(uid) => uid,
+                None => db.next_uid()?,
+            };
+            let mut shell = user.shell.clone().unwrap_or(s!(consts::DEFAULT_SHELL));
+            if user.state == AccountState::Disabled {
+                shell = s!(consts::NOLOGIN_SHELL);
+            }
+            db.passwd.insert(&[
+                &user.username,
+                "x",
+                &uid.to_string(),
+                &gid.to_string(),
+                user.gecos.as_deref().unwrap_or_default(),
+                &home,
+                &shell,
+            ]);
+
+            // A new account is created with a locked password, a disabled one is also expired.
+            let [minimum, maximum, warn] = db.aging_defaults.clone();
+            db.shadow.insert(&[
+                &user.username,
+                "!",
+                &date::today().to_string(),
+                &minimum,
+                &maximum,
+                &warn,
+                "",
+                "",
+                "",
+            ]);
+            for (field, value) in user.aging_fields() {
+                db.shadow
+                    .set(&user.username, aging_field(field), days(value))?;
+            }
+            if user.state == AccountState::Disabled {
+                let expire = consts::DISABLED_EXPIRE.to_string();
+                db.shadow
+                    .set(&user.username, consts::EXPIRE_FIELD, expire)?;
+            }
+
+            db.set_membership(&user.username, &user.groups[1..])?;
+            Ok((uid, gid))
+        })?;
+
+        self.apply_home(HomeAction::Create {
+            home,
+            uid,
+            gid,
+            skel: policy.skel.clone().unwrap_or(s!(consts::SKEL_DIR)),
+            mode: policy.mode().unwrap_or(HOME_MODE),
+        })
+    }
+
+    fn modify_user(&mut self, user: &User, change: &UserChange) -> io::Result<()> {
+        // The SSH keys are outside of the user database.
+        if let UserChange::SshKeys { .. } = change {
+            if self.dry_run {
+                if !self.quiet {
+                    println!("Skipped (dry-run)");
+                    println!(
+                        "\twrite ~{}/{}",
+                        user.username,
+                        consts::AUTHORIZED_KEYS_FILE
+                    );
+                }
+                return Ok(());
+            }
+
+            return ssh::write_authorized_keys(
+                &self.root,
+                &user.username,
+                &user.ssh_key_lines().unwrap_or_default(),
+                user.ssh_keys_mode,
+            );
+        }
+
+        let name = &user.username;
+        let home = self.apply(|db| {
+            let home = s!(db.passwd.field(name, consts::HOME_FIELD)?);
+            match change {
+                UserChange::PrimaryGroup { to, .. } => {
+                    let from = db.passwd.id(name, consts::GID_FIELD)?;
+                    let to = db.group.id(to, consts::GID_FIELD)?;
+                    db.passwd.set(name, consts::GID_FIELD, to.to_string())?;
+                    Ok(Some(HomeAction::Chown {
+                        home,
+                        uid: None,
+                        gid: Some((from, to)),
+                    }))
+                }
+                UserChange::SupplementaryGroups { add, remove } => {
+                    let mut groups = Vec::<String>::new();
+                    for group in db.group.names() {
+                        let members = db.group.list(&group, consts::USER_LIST_FIELD)?;
+                        if members.contains(name) && !remove.contains(&group) {
+                            groups.push(group);
+                        }
+                    }
+                    groups.extend(add.iter().cloned());
+                    db.set_membership(name, &groups)?;
+                    Ok(None)
+                }
+                UserChange::Uid { to, .. } => {
+                    let from = db.passwd.id(name, consts::UID_FIELD)?;
+                    db.passwd.set(name, consts::UID_FIELD, to.to_string())?;
+                    Ok(Some(HomeAction::Chown {
+                        home,
+                        uid: Some((from, *to)),
+                        gid: None,
+                    }))
+                }
+                UserChange::Gecos { to, .. } => {
+                    db.passwd.set(name, consts::GECOS_FIELD, to.clone())?;
+                    Ok(None)
+                }
+                // The content of the current home directory is moved to the new one.
+                UserChange::Home { to, .. } => {
+                    db.passwd.set(name, consts::HOME_FIELD, to.clone())?;
+                    Ok(Some(HomeAction::Move {
+                        from: home,
+                        to: to.clone(),
+                    }))
+                }
+                UserChange::Shell { to, .. } => {
+                    db.passwd.set(name, consts::SHELL_FIELD, to.clone())?;
+                    Ok(None)
+                }
+                UserChange::Aging { field, to, .. } => {
+                    db.shadow.set(name, aging_field(*field), days(*to))?;
+                    Ok(None)
+                }
+                UserChange::State { from, to } => {
+                    db.set_state(user, *from, *to)?;
+                    Ok(None)
+                }
+                UserChange::SshKeys { .. } => Ok(None),
+                // Passwords are applied all at once by `set_passwords`.
+                UserChange::Password => Ok(None),
+            }
+        })?;
+
+        match home {
+            Some(action) => self.apply_home(action),
+            None => Ok(()),
+        }
+    }
+
+    fn delete_user(&mut self, user: &SystemUser, policy: &HomePolicy) -> io::Result<()> {
+        let home = self.apply(|db| {
+            let home = s!(db.passwd.field(&user.username, consts::HOME_FIELD)?);
+            db.passwd.remove(&user.username)?;
+            db.shadow.remove(&user.username).ok();
+            db.set_membership(&user.username, &[])?;
+            Ok(home)
+        })?;
+
+        // Like `userdel` without `--remove`, the home directory is kept by default.
+        let archive = match policy.remove.unwrap_or_default() {
+            HomeRemoval::Keep => return Ok(()),
+            HomeRemoval::Delete => None,
+            HomeRemoval::Archive => Some(policy.archive_file(&user.username)),
+        };
+        self.apply_home(HomeAction::Remove { home, archive })
+    }
+
+    fn set_passwords(&mut self, users: &[User], encrypted: bool) -> io::Result<()> {
+        let errors = self.apply(|db| {
+            let mut errors = Vec::<String>::new();
+            for user in users {
+                let hash = match encrypted {
+                    true => Ok(user.password.clone()),
+                    false => pwhash::sha512_crypt::hash(&user.password).map_err(|e| {
+                        operation_error(format!(
+                            "can't hash the password of '{}': {}",
+                            user.username, e
+                        ))
+                    }),
+                };
+                let rst = hash.and_then(|hash| {
+                    db.shadow
+                        .set(&user.username, consts::PASSWORD_FIELD, hash)?;
+                    db.shadow.set(
+                        &user.username,
+                        consts::LAST_CHANGED_FIELD,
+                        date::today().to_string(),
+                    )
+                });
+                if let Err(e) = rst {
+                    errors.push(e.to_string());
+                }
+            }
+
+            Ok(errors)
+        })?;
+
+        match errors.is_empty() {
+            true => Ok(()),
+            false => Err(operation_error(errors.join("\n"))),
+        }
+    }
+
+    fn set_sudoers(&mut self, change: &SudoersChange) -> io::Result<()> {
+        if self.dry_run {
+            if !self.quiet {
+                println!("Skipped (dry-run)");
+                match change {
+                    SudoersChange::Write(_) => println!("\twrite {}", consts::SUDOERS_FILE),
+                    SudoersChange::Remove => println!("\tremove {}", consts::SUDOERS_FILE),
+                }
+            }
+            return Ok(());
+        }
+
+        let content = match change {
+            SudoersChange::Write(content) => Some(content.as_str()),
+            SudoersChange::Remove => None,
+        };
+        sudo::install(&self.root, content)
+    }
+}
+
+/**
+The user database files.
+ */
+#[derive(Debug, Clone)]
+struct Database {
+    passwd: Table,
+    shadow: Table,
+    group: Table,
+    /// The group password file, which doesn't exist on every system.
+    gshadow: Option<Table>,
+    /// The filter selecting the ids allocated to the new users and groups.
+    filter: IdFilter,
+    /// The minimum, maximum and warning days of the new users, from `/etc/login.defs`.
+    aging_defaults: [String; 3],
+}
+
+impl Database {
+    /**
+    Read the user database.
+
+    # Parameters
+    * `root`: The root of the filesystem containing the files.
+    * `filter`: The filter selecting the ids allocated to the new users and groups.
+     */
+    fn read(root: &Path, filter: &IdFilter) -> io::Result<Self> {
+        let gshadow_path = system_parser::system_path(root, consts::GROUP_PASSWORD_FILE);
+        let defs = LoginDefs::read(root)?;
+        let default = |key: &str| defs.get(key).map(|v| v.to_string()).unwrap_or_default();
+
+        Ok(Self {
+            passwd: Table::read(root, consts::USER_FILE, consts::USER_FIELDS)?,
+            shadow: Table::read(root, consts::PASSWORD_FILE, consts::PASSWORD_FIELDS)?,
+            group: Table::read(root, consts::GROUP_FILE, consts::GROUP_FIELDS)?,
+            gshadow: match gshadow_path.exists() {
+                true => Some(Table::read(
+                    root,
+                    consts::GROUP_PASSWORD_FILE,
+                    consts::GROUP_PASSWORD_FIELDS,
+                )?),
+                false => None,
+            },
+            filter: filter.clone(),
+            aging_defaults: [
+                default("PASS_MIN_DAYS"),
+                default("PASS_MAX_DAYS"),
+                default("PASS_WARN_AGE"),
+            ],
+        })
+    }
+
+    /**
+    Get every table of the database.
+     */
+    fn tables_mut(&mut self) -> impl Iterator<Item = &mut Table> {
+        vec![&mut self.passwd, &mut self.shadow, &mut self.group]
+            .into_iter()
+            .chain(self.gshadow.as_mut())
+    }
+
+    /**
+    Get the next free UID, after the highest UID handled by the application.
+     */
+    fn next_uid(&self) -> io::Result<u32> {
+        self.filter
+            .next_uid(&table_ids(&self.passwd, consts::UID_FIELD))
+    }
+
+    /**
+    Get the next free GID, after the highest GID handled by the application.
+     */
+    fn next_gid(&self) -> io::Result<u32> {
+        self.filter
+            .next_gid(&table_ids(&self.group, consts::GID_FIELD))
+    }
+
+    /**
+    Set the supplementary groups of a user, in the group and group password files.
+
+    # Parameters
+    * `username`: The name of the user.
+    * `groups`: The name of every supplementary groups of the user.
+     */
+    fn set_membership(&mut self, username: &str, groups: &[String]) -> io::Result<()> {
+        for name in groups {
+            self.group.field(name, consts::NAME_FIELD)?;
+        }
+
+        let mut tables = vec![&mut self.group];
+        tables.extend(self.gshadow.as_mut());
+        for table in tables {
+            for name in table.names() {
+                let mut members = table.list(&name, consts::USER_LIST_FIELD)?;
+                members.retain(|m| m != username);
+                if groups.contains(&name) {
+                    members.push(s!(username));
+                }
+                table.set(&name, consts::USER_LIST_FIELD, members.join(","))?;
+            }
+        }
+
+        // A deleted user is no longer a group administrator.
+        if groups.is_empty() {
+            if let Some(gshadow) = &mut self.gshadow {
+                for name in gshadow.names() {
+                    let mut admins = gshadow.list(&name, consts::ADMIN_LIST_FIELD)?;
+                    admins.retain(|a| a != username);
+                    gshadow.set(&name, consts::ADMIN_LIST_FIELD, admins.join(","))?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /**
+    Change the state of an account, like `usermod --lock`, `--unlock` and `--expiredate`.
+
+    # Parameters
+    * `user`: The user, as defined in the sync file.
+    * `from`: The current state of the account.
+    * `to`: The new state of the account.
+     */
+    fn set_state(&mut self, user: &User, from: AccountState, to: AccountState) -> io::Result<()> {
+        let name = &user.username;
+        let hash = s!(self.shadow.field(name, consts::PASSWORD_FIELD)?);
+        match (from == AccountState::Active, to == AccountState::Active) {
+            (true, false) => self
+                .shadow
+                .set(name, consts::PASSWORD_FIELD, format!("!{}", hash))?,
+            (false, true) => {
+                // Like `usermod --unlock`, an empty password can't be unlocked.
+                if hash.len() <= 1 {
+                    return Err(operation_error(format!(
+                        "unlocking the password of '{}' would result in a passwordless account",
+                        name
+                    )));
+                }
+                self.shadow
+                    .set(name, consts::PASSWORD_FIELD, s!(&hash[1..]))?;
+            }
+            _ => {}
+        }
+
+        // Disabling expires the account and prevents the login,
+        // the expiry date and shell of the sync file are restored on enabling.
+        if to == AccountState::Disabled {
+            let expire = consts::DISABLED_EXPIRE.to_string();
+            self.shadow.set(name, consts::EXPIRE_FIELD, expire)?;
+            self.passwd
+                .set(name, consts::SHELL_FIELD, s!(consts::NOLOGIN_SHELL))?;
+        } else if from == AccountState::Disabled {
+            let expire = days(user.aging.expire_days());
+            let shell = user.shell.clone().unwrap_or(s!(consts::DEFAULT_SHELL));
+            self.shadow.set(name, consts::EXPIRE_FIELD, expire)?;
+            self.passwd.set(name, consts::SHELL_FIELD, shell)?;
+        }
+
+        Ok(())
+    }
+}
+
+/**
+A user database file, with an entry per line.
+ */
+#[derive(Debug, Clone)]
+struct Table {
+    path: PathBuf,
+    fields: &'static [&'static str],
+    entries: Vec<RawEntry>,
+    /// The entries were modified since the file was written.
+    modified: bool,
+    /// The original file was saved as a backup.
+    backed_up: bool,
+}
+
+impl Table {
+    /**
+    Read a user database file.
+
+    # Parameters
+    * `root`: The root of the filesystem containing the file.
+    * `file`: The absolute path of the file on the live system (e.g. `consts::USER_FILE`).
+    * `fields`: The list of all the fields of the file.
+     */
+    fn read(root: &Path, file: &str, fields: &'static [&'static str]) -> io::Result<Self> {
+        let path = system_parser::system_path(root, file);
+        let mut entries = system_parser::parse_system_file(&path, fields)?;
+        // Blank lines are dropped.
+        entries.retain(|entry| entry.get(consts::NAME_FIELD).is_some_and(|n| !n.is_empty()));
+
+        Ok(Self {
+            path,
+            fields,
+            entries,
+            modified: false,
+            backed_up: false,
+        })
+    }
+
+    /**
+    Find an entry by name.
+
+    # Parameters
+    * `name`: The name of the user or group.
+     */
+    fn get(&self, name: &str) -> Option<&RawEntry> {
+        self.entries
+            .iter()
+            .find(|entry| entry.get(consts::NAME_FIELD).is_some_and(|n| n == name))
+    }
+
+    /**
+    Get the name of every entry.
+     */
+    fn names(&self) -> Vec<String> {
+        self.entries
+            .iter()
+            .filter_map(|entry| entry.get(consts::NAME_FIELD).cloned())
+            .collect()
+    }
+
+    /**
+    Get a field of an entry.
+
+    # Parameters
+    * `name`: The name of the user or group.
+    * `field`: The name of the field.
+
+    # Returns
+    The value of the field, or an error when there's no entry for the name.
+     */
+    fn field(&self, name: &str, field: &str) -> io::Result<&str> {
+        self.get(name)
+            .map(|entry| entry.get(field).map_or("", |v| v.as_str()))
+            .ok_or_else(|| self.not_found(name))
+    }
+
+    /**
+    Get a numeric id field of an entry.
+
+    # Parameters
+    * `name`: The name of the user or group.
+    * `field`: The name of the field.
+     */
+    fn id(&self, name: &str, field: &str) -> io::Result<u32> {
+        let value = self.field(name, field)?;
+        value.parse().map_err(|_| {
+            operation_error(format!(
+                "invalid {} `{}` for `{}` in {}",
+                field,
+                value,
+                name,
+                self.path.display()
+            ))
+        })
+    }
+
+    /**
+    Get a comma separated list field of an entry.
+
+    # Parameters
+    * `name`: The name of the user or group.
+    * `field`: The name of the field.
+     */
+    fn list(&self, name: &str, field: &str) -> io::Result<Vec<String>> {
+        Ok(self
+            .field(name, field)?
+            .split(',')
+            .filter(|v| !v.is_empty())
+            .map(|v| s!(v))
+            .collect())
+    }
+
+    /**
+    Set a field of an entry.
+
+    # Parameters
+    * `name`: The name of the user or group.
+    * `field`: The name of the field.
+    * `value`: The new value.
+
+    # Returns
+    An error when there's no entry for the name.
+     */
+    fn set(&mut self, name: &str, field: &str, value: String) -> io::Result<()> {
+        let not_found = self.not_found(name);
+        let entry = self
+            .entries
+            .iter_mut()
+            .find(|entry| entry.get(consts::NAME_FIELD).is_some_and(|n| n == name))
+            .ok_or(not_found)?;
+
+        if entry.get(field) != Some(&value) {
+            entry.insert(s!(field), value);
+            self.modified = true;
+        }
+
+        Ok(())
+    }
+
+    /**
+    Append an entry.
+
+    # Parameters
+    * `values`: The value of every field, in the order of the file.
+     */
+    fn insert(&mut self, values: &[&str]) {
+        let entry = self
+            .fields
+            .iter()
+            .zip(values.iter().chain(std::iter::repeat(&"")))
+            .map(|(field, value)| (s!(*field), s!(*value)))
+            .collect();
+        self.entries.push(entry);
+        self.modified = true;
+    }
+
+    /**
+    Remove an entry.
+
+    # Parameters
+    * `name`: The name of the user or group.
+
+    # Returns
+    An error when there's no entry for the name.
+     */
+    fn remove(&mut self, name: &str) -> io::Result<()> {
+        self.get(name).ok_or_else(|| self.not_found(name))?;
+        self.entries
+            .retain(|entry| entry.get(consts::NAME_FIELD).is_none_or(|n| n != name));
+        self.modified = true;
+
+        Ok(())
+    }
+
+    /**
+    Write the file when it was modified.
+    The content is written to `<file>+` then renamed over the file,
+    the original file is saved once as `<file>-` like the shadow-utils commands do.
+     */
+    fn write(&mut self) -> io::Result<()> {
+        if !self.modified {
+            return Ok(());
+        }
+
+        let metadata = fs::metadata(&self.path)?;
+        if !self.backed_up {
+            let backup = suffixed(&self.path, "-");
+            fs::copy(&self.path, &backup)?;
+            unix_fs::chown(&backup, Some(metadata.uid()), Some(metadata.gid()))?;
+            self.backed_up = true;
+        }
+
+        let mut content = String::new();
+        for entry in &self.entries {
+            content.push_str(&system_parser::format_system_entry(entry, self.fields));
+            content.push('\n');
+        }
+
+        let tmp = suffixed(&self.path, "+");
+        match fs::remove_file(&tmp) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(&tmp)?;
+        file.write_all(content.as_bytes())?;
+        file.sync_all()?;
+        fs::set_permissions(&tmp, metadata.permissions())?;
+        unix_fs::chown(&tmp, Some(metadata.uid()), Some(metadata.gid()))?;
+        fs::rename(&tmp, &self.path)?;
+
+        self.modified = false;
+        Ok(())
+    }
+
+    /**
+    Create the error of a missing entry.
+
+    # Parameters
+    * `name`: The name of the user or group.
+     */
+    fn not_found(&self, name: &str) -> io::Error {
+        operation_error(format!(
+            "'{}' does not exist in {}",
+            name,
+            self.path.display()
+        ))
+    }
+}
+
+/**
+The lock of the user database.
+The lock of `/etc/.pwd.lock` is taken like `lckpwdf()` and every file is locked with
+a `<file>.lock` file containing the PID, like the shadow-utils commands do.
+The locks are released when dropped.
+ */
+#[derive(Debug)]
+struct Lock {
+    /// The database lock file, its lock is released when it is closed.
+    _database: File,
+    /// The lock files of the user database files.
+    files: Vec<PathBuf>,
+}
+
+impl Lock {
+    /**
+    Lock the user database.
+
+    # Parameters
+    * `root`: The root of the filesystem containing the files.
+
+    # Returns
+    The lock, or an error when the database is locked by another process.
+     */
+    fn acquire(root: &Path) -> io::Result<Self> {
+        let path = system_parser::system_path(root, consts::DATABASE_LOCK_FILE);
+        let database = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .mode(0o600)
+            .open(&path)?;
+        lock_database(&database, &path)?;
+
+        let mut lock = Self {
+            _database: database,
+            files: vec![],
+        };
+        for file in [
+            consts::USER_FILE,
+            consts::PASSWORD_FILE,
+            consts::GROUP_FILE,
+            consts::GROUP_PASSWORD_FILE,
+        ] {
+            let path = system_parser::system_path(root, file);
+            if path.exists() {
+                lock.files.push(lock_file(&path)?);
+            }
+        }
+
+        Ok(lock)
+    }
+}
+
+impl Drop for Lock {
+    fn drop(&mut self) {
+        for path in &self.files {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+/**
+Take the `lckpwdf()` lock, a write lock on the whole database lock file.
+Like `lckpwdf()`, it waits for the lock up to 15 seconds.
+
+# Parameters
+* `file`: The opened database lock file.
+* `path`: The path of the database lock file.
+ */
+fn lock_database(file: &File, path: &Path) -> io::Result<()> {
+    let start = Instant::now();
+    loop {
+        // SAFETY: `flock` is a plain C struct for which zeroes are valid,
+        // the descriptor stays open for the duration of the call.
+        let rst = unsafe {
+            let mut lock: libc::flock = std::mem::zeroed();
+            lock.l_type = libc::F_WRLCK as libc::c_short;
+            lock.l_whence = libc::SEEK_SET as libc::c_short;
+            libc::fcntl(file.as_raw_fd(), libc::F_SETLK, &lock)
+        };
+        if rst == 0 {
+            return Ok(());
+        }
+
+        let e = io::Error::last_os_error();
+        let busy = matches!(e.raw_os_error(), Some(libc::EAGAIN) | Some(libc::EACCES));
+        if !busy {
+            return Err(e);
+        }
+        if start.elapsed() >= LOCK_TIMEOUT {
+            return Err(operation_error(format!(
+                "{} is locked by another process",
+                path.display()
+            )));
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+}
+
+/**
+Lock a user database file with a `<file>.lock` file containing the PID.
+A lock left by a process which no longer exists is removed.
+
+# Parameters
+* `path`: The path of the file to lock.
+
+# Returns
+The path of the lock file.
+ */
+fn lock_file(path: &Path) -> io::Result<PathBuf> {
+    let lock_path = suffixed(path, ".lock");
+    for _ in 0..2 {
+        match OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(&lock_path)
+        {
+            Ok(mut file) => {
+                file.write_all(process::id().to_string().as_bytes())?;
+                return Ok(lock_path);
+            }
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                let pid = fs::read_to_string(&lock_path)?;
+                let pid = pid.trim();
+                let alive = !pid.is_empty() && Path::new("/proc").join(pid).exists();
+                if alive {
+                    return Err(operation_error(format!(
+                        "{} is locked by process {}",
+                        path.display(),
+                        pid
+                    )));
+                }
+                fs::remove_file(&lock_path)?;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+
+    Err(operation_error(format!(
+        "{} couldn't be locked",
+        path.display()
+    )))
+}
+
+/**
+An action applied to a home directory once the user database is written.
+ */
+#[derive(Debug)]
+enum HomeAction {
+    /// Create the directory from the skeleton directory, like `useradd --create-home`.
+    Create {
+        home: String,
+        uid: u32,
+        gid: u32,
+        skel: String,
+        mode: u32,
+    },
+    /// Move the directory, like `usermod --move-home`.
+    Move { from: String, to: String },
+    /// Change the owner (from, to) of the files, like `usermod --uid` and `--gid`.
+    Chown {
+        home: String,
+        uid: Option<(u32, u32)>,
+        gid: Option<(u32, u32)>,
+    },
+    /// Remove the directory, like `userdel --remove`, once archived when an archive is set.
+    Remove {
+        home: String,
+        archive: Option<String>,
+    },
+}
+
+impl fmt::Display for HomeAction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Create {
+                home,
+                uid,
+                gid,
+                skel,
+                mode,
+            } => write!(
+                f,
+                "create home {} ({}:{}, mode {:04o}) from {}",
+                home, uid, gid, mode, skel
+            ),
+            Self::Move { from, to } => write!(f, "move home {} -> {}", from, to),
+            Self::Chown { home, uid, gid } => {
+                let ids = |ids: &Option<(u32, u32)>| match ids {
+                    Some((from, to)) => format!("{} -> {}", from, to),
+                    None => s!("unchanged"),
+                };
+                write!(
+                    f,
+                    "change owner of home {}: UID {}, GID {}",
+                    home,
+                    ids(uid),
+                    ids(gid)
+                )
+            }
+            Self::Remove {
+                home,
+                archive: Some(archive),
+            } => write!(f, "archive home {} to {} and remove it", home, archive),
+            Self::Remove {
+                home,
+                archive: None,
+            } => write!(f, "remove home {}", home),
+        }
+    }
+}
+
+/**
+Get the id of every entry of a table.
+
+# Parameters
+* `table`: The table of the users or groups.
+* `field`: The name of the id field.
+
+# Returns
+The name and id of the entries, the entries with an invalid id are skipped.
+ */
+fn table_ids(table: &Table, field: &str) -> Vec<(String, u32)> {
+    table
+        .names()
+        .into_iter()
+        .filter_map(|name| table.id(&name, field).ok().map(|id| (name, id)))
+        .collect()
+}
+
+/**
+Get the shadow field of an aging field.
+
+# Parameters
+* `field`: The aging field.
+ */
+fn aging_field(field: AgingField) -> &'static str {
+    match field {
+        AgingField::Minimum => consts::MINIMUM_FIELD,
+        AgingField::Maximum => consts::MAXIMUM_FIELD,
+        AgingField::Warn => consts::WARN_FIELD,
+        AgingField::Inactive => consts::INACTIVE_FIELD,
+        AgingField::Expire => consts::EXPIRE_FIELD,
+    }
+}
+
+/**
+Format a number of days for the shadow file.
+
+# Parameters
+* `value`: The number of days, `None` for an empty field.
+ */
+fn days(value: Option<i64>) -> String {
+    value.map(|v| v.to_string()).unwrap_or_default()
+}
+
+/**
+Append a suffix to a path (e.g. `/etc/passwd` and `-` gives `/etc/passwd-`).
+
+# Parameters
+* `path`: The path.
+* `suffix`: The suffix.
+ */
+fn suffixed(path: &Path, suffix: &str) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push(suffix);
+    PathBuf::from(path)
+}
+
+/**
+Archive a directory in a gzipped tarball with `tar`, the archive is only readable by `root`
+and a missing archive directory is created only readable by `root`.
+
+# Parameters
+* `dir`: The directory to archive.
+* `archive`: The path of the archive, its parent directory is created when missing.
+ */
+fn archive_tree(dir: &Path, archive: &Path) -> io::Result<()> {
+    let (Some(parent), Some(name)) = (dir.parent(), dir.file_name()) else {
+        return Err(operation_error(format!("can't archive {}", dir.display())));
+    };
+    if let Some(archive_dir) = archive.parent() {
+        create_archive_dir(archive_dir)?;
+    }
+
+    let output = process::Command::new(consts::ARCHIVE_CMD)
+        .arg("-czf")
+        .arg(archive)
+        .arg("-C")
+        .arg(parent)
+        .arg(name)
+        .output()?;
+    match output.status.success() {
+        true => Ok(()),
+        false => Err(operation_error(format!(
+            "can't archive {} to {} ({})\n{}",
+            dir.display(),
+            archive.display(),
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim_end()
+        ))),
+    }
+}
+
+/**
+Copy the content of a directory, the copies are owned by the given user and group.
+
+# Parameters
+* `from`: The directory to copy.
+* `to`: The existing destination directory.
+* `uid`: The owner of the copies.
+* `gid`: The group of the copies.
+ */
+fn copy_tree(from: &Path, to: &Path, uid: u32, gid: u32) -> io::Result<()> {
+    for entry in fs::read_dir(from)? {
+        let entry = entry?;
+        let target = to.join(entry.file_name());
+        let file_type = entry.file_type()?;
+
+        if file_type.is_dir() {
+            fs::create_dir(&target)?;
+            fs::set_permissions(&target, entry.metadata()?.permissions())?;
+            copy_tree(&entry.path(), &target, uid, gid)?;
+        } else if file_type.is_symlink() {
+            unix_fs::symlink(fs::read_link(entry.path())?, &target)?;
+        } else {
+            fs::copy(entry.path(), &target)?;
+        }
+        unix_fs::lchown(&target, Some(uid), Some(gid))?;
+    }
+
+    Ok(())
+}
+
+/**
+Change the owner of a directory and its content, only the files owned by the previous ids are changed.
+
+# Parameters
+* `path`: The directory.
+* `uid`: The previous (0) and new (1) owner, `None` to keep it.
+* `gid`: The previous (0) and new (1) group, `None` to keep it.
+ */
+fn chown_tree(path: &Path, uid: Option<(u32, u32)>, gid: Option<(u32, u32)>) -> io::Result<()> {
+    let metadata = fs::symlink_metadata(path)?;
+    let new_uid = uid
+        .filter(|(from, _)| *from == metadata.uid())
+        .map(|(_, to)| to);
+    let new_gid = gid
+        .filter(|(from, _)| *from == metadata.gid())
+        .map(|(_, to)| to);
+    if new_uid.is_some() || new_gid.is_some() {
+        unix_fs::lchown(path, new_uid, new_gid)?;
+    }
+
+    if metadata.is_dir() {
+        for entry in fs::read_dir(path)? {
+            chown_tree(&entry?.path(), uid, gid)?;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sync_structs::LocalRoot;
+
+    const PASSWD: &str = "root:x:0:0:root:/root:/bin/bash\n\
+                          nobody:x:65534:65534::/nonexistent:/usr/sbin/nologin\n";
+    const SHADOW: &str = "root:*:19000:0:99999:7:::\nnobody:*:19000:0:99999:7:::\n";
+    const GROUP: &str = "root:x:0:\nnogroup:x:65534:\n";
+    const GSHADOW: &str = "root:*::\nnogroup:*::\n";
+
+    /**
+    Create a filesystem root with a minimal user database, removed by the test once done.
+
+    # Parameters
+    * `name`: The name of the test, so the tests don't share a root.
+     */
+    fn temp_root(name: &str) -> PathBuf {
+        let root = std::env::temp_dir().join(format!("user-sync-{}-{}", name, process::id()));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(root.join("etc")).unwrap();
+        for (file, content) in [
+            (consts::USER_FILE, PASSWD),
+            (consts::PASSWORD_FILE, SHADOW),
+            (consts::GROUP_FILE, GROUP),
+            (consts::GROUP_PASSWORD_FILE, GSHADOW),
+        ] {
+            fs::write(system_parser::system_path(&root, file), content).unwrap();
+        }
+        // Like `useradd`, the existing home directory is left untouched.
+        fs::create_dir_all(root.join("home/alice")).unwrap();
+        root
+    }
+
+    /**
+    Read a file of the user database.
+
+    # Parameters
+    * `root`: The root of the filesystem.
+    * `file`: The path of the file on the live system (e.g. `/etc/passwd`).
+     */
+    fn read(root: &Path, file: &str) -> String {
+        fs::read_to_string(system_parser::system_path(root, file)).unwrap()
+    }
+
+    /**
+    Parse the local section of a sync file with alice in the devs group.
+     */
+    fn local_root() -> LocalRoot {
+        let mut sync: LocalRoot = serde_json::from_str(
+            r#"{
+                "encrypted": true,
+                "users": [{ "username": "alice", "password": "$6$salt$alice", "groups": ["devs"] }],
+                "groups": [{ "name": "devs" }]
+            }"#,
+        )
+        .unwrap();
+        sync.resolve_members();
+        sync
+    }
+
+    #[test]
+    fn users_and_groups_are_added_modified_and_removed() {
+        let root = temp_root("round-trip");
+        let sync = local_root();
+        let (alice, devs) = (&sync.users[0], &sync.groups[0]);
+        let mut backend = Files::new(false, true, &root, &IdFilter::default());
+
+        backend.add_group(devs).unwrap();
+        backend.add_user(alice, &HomePolicy::default()).unwrap();
+        backend.set_passwords(&sync.users, true).unwrap();
+        backend
+            .modify_user(
+                alice,
+                &UserChange::Shell {
+                    from: s!(consts::DEFAULT_SHELL),
+                    to: s!("/bin/zsh"),
+                },
+            )
+            .unwrap();
+
+        // The entries it doesn't manage are kept as they were, in the same order.
+        assert_eq!(
+            read(&root, consts::USER_FILE),
+            format!("{}alice:x:1000:1000::/home/alice:/bin/zsh\n", PASSWD)
+        );
+        assert_eq!(
+            read(&root, consts::GROUP_FILE),
+            format!("{}devs:x:1000:\n", GROUP)
+        );
+        assert_eq!(
+            read(&root, consts::GROUP_PASSWORD_FILE),
+            format!("{}devs:!::\n", GSHADOW)
+        );
+        let shadow = read(&root, consts::PASSWORD_FILE);
+        assert!(shadow.starts_with(SHADOW), "{}", shadow);
+        assert!(
+            shadow.contains(&format!("alice:$6$salt$alice:{}:", date::today())),
+            "{}",
+            shadow
+        );
+
+        let system_alice = SystemUser {
+            username: s!("alice"),
+            hash: s!("$6$salt$alice"),
+            uid: 1000,
+            gid: 1000,
+            gecos: String::new(),
+            home: s!("/home/alice"),
+            shell: s!("/bin/zsh"),
+            aging: Default::default(),
+            authorized_keys: Default::default(),
+        };
+        let system_devs = SystemGroup {
+            name: s!("devs"),
+            gid: 1000,
+            users: vec![],
+        };
+        backend
+            .delete_user(&system_alice, &HomePolicy::default())
+            .unwrap();
+        backend.delete_group(&system_devs).unwrap();
+        drop(backend);
+
+        for (file, content) in [
+            (consts::USER_FILE, PASSWD),
+            (consts::PASSWORD_FILE, SHADOW),
+            (consts::GROUP_FILE, GROUP),
+            (consts::GROUP_PASSWORD_FILE, GSHADOW),
+        ] {
+            assert_eq!(read(&root, file), content);
+        }
+        // The home directory is kept by default.
+        assert!(root.join("home/alice").is_dir());
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn original_files_are_backed_up_once() {
+        let root = temp_root("backup");
+        let sync = local_root();
+        let mut backend = Files::new(false, true, &root, &IdFilter::default());
+
+        backend.add_group(&sync.groups[0]).unwrap();
+        backend
+            .add_user(&sync.users[0], &HomePolicy::default())
+            .unwrap();
+        drop(backend);
+
+        // The backup is the file before the first operation of the run, not the previous operation.
+        let backup = |file: &str| read(&root, &format!("{}-", file));
+        assert_eq!(backup(consts::USER_FILE), PASSWD);
+        assert_eq!(backup(consts::GROUP_FILE), GROUP);
+        assert_ne!(read(&root, consts::GROUP_FILE), GROUP);
+        // The temporary files are renamed over the files.
+        assert!(!root.join("etc/passwd+").exists());
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn dry_run_leaves_the_files_untouched() {
+        let root = temp_root("dry-run");
+        let sync = local_root();
+        let mut backend = Files::new(true, true, &root, &IdFilter::default());
+
+        backend.add_group(&sync.groups[0]).unwrap();
+        drop(backend);
+
+        assert_eq!(read(&root, consts::GROUP_FILE), GROUP);
+        assert!(!root.join("etc/group-").exists());
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn locked_database_is_not_modified() {
+        let root = temp_root("lock");
+        let sync = local_root();
+        let lock = root.join("etc/group.lock");
+
+        // The lock of a running process is respected.
+        fs::write(&lock, process::id().to_string()).unwrap();
+        let mut backend = Files::new(false, true, &root, &IdFilter::default());
+        let error = backend.add_group(&sync.groups[0]).unwrap_err();
+        assert!(
+            error
+                .to_string()
+                .contains(&format!("group is locked by process {}", process::id())),
+            "{}",
+            error
+        );
+        drop(backend);
+        assert_eq!(read(&root, consts::GROUP_FILE), GROUP);
+        // The locks taken before the failure are released.
+        assert!(!root.join("etc/passwd.lock").exists());
+        assert!(lock.exists());
+
+        // The lock left by a process which no longer exists is taken over.
+        fs::write(&lock, "").unwrap();
+        let mut backend = Files::new(false, true, &root, &IdFilter::default());
+        backend.add_group(&sync.groups[0]).unwrap();
+        assert_eq!(
+            fs::read_to_string(&lock).unwrap(),
+            process::id().to_string()
+        );
+        drop(backend);
+        assert!(!lock.exists());
+        assert_ne!(read(&root, consts::GROUP_FILE), GROUP);
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
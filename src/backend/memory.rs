@@ -0,0 +1,316 @@
+use std::io;
+
+use super::{operation_error, Backend};
+use crate::{consts, filter::IdFilter, s, ssh::AuthorizedKeys};
+use crate::{
+    differ::{GroupChange, SudoersChange, UserChange},
+    sync_structs::{AccountState, Group, HomePolicy, User},
+    system_parser::{ShadowAging, SystemGroup, SystemUser},
+};
+
+/**
+Backend applying the changes to an in-memory copy of the system database.
+
+Nothing is written to the system, it is used to exercise a whole sync without root privileges.
+The rules of the shadow-utils commands are followed so the same operations fail.
+ */
+#[derive(Debug, Default)]
+pub struct Memory {
+    pub users: Vec<SystemUser>,
+    pub groups: Vec<SystemGroup>,
+    /// The filter selecting the ids allocated to the new users and groups.
+    pub filter: IdFilter,
+    /// When `true`, the progress of the operations isn't printed.
+    pub quiet: bool,
+}
+
+impl Memory {
+    /**
+    Create a new in-memory backend.
+
+    # Parameters
+    * `system`: The tuple with the list of system users (0) and system groups (1) to start from.
+    * `filter`: The filter selecting the ids allocated to the new users and groups.
+    * `quiet`: When `true`, nothing is printed.
+     */
+    pub fn new(
+        system: (Vec<SystemUser>, Vec<SystemGroup>),
+        filter: &IdFilter,
+        quiet: bool,
+    ) -> Self {
+        let (users, groups) = system;
+        Self {
+            users,
+            groups,
+            filter: filter.clone(),
+            quiet,
+        }
+    }
+
+    /**
+    Find a group by name.
+
+    # Parameters
+    * `name`: The name of the group.
+
+    # Returns
+    The group, or an error when it doesn't exist.
+     */
+    fn group(&self, name: &str) -> io::Result<&SystemGroup> {
+        self.groups
+            .iter()
+            .find(|g| g.name == name)
+            .ok_or_else(|| operation_error(format!("group '{}' does not exist", name)))
+    }
+
+    /**
+    Find a user by name.
+
+    # Parameters
+    * `username`: The name of the user.
+
+    # Returns
+    The user, or an error when it doesn't exist.
+     */
+    fn user_mut(&mut self, username: &str) -> io::Result<&mut SystemUser> {
+        self.users
+            .iter_mut()
+            .find(|u| u.username == username)
+            .ok_or_else(|| operation_error(format!("user '{}' does not exist", username)))
+    }
+
+    /**
+    Set the supplementary groups of a user.
+
+    # Parameters
+    * `username`: The name of the user.
+    * `groups`: The name of every supplementary groups of the user.
+     */
+    fn set_membership(&mut self, username: &str, groups: &[String]) -> io::Result<()> {
+        for name in groups {
+            self.group(name)?;
+        }
+
+        for group in self.groups.iter_mut() {
+            group.users.retain(|u| u != username);
+            if groups.contains(&group.name) {
+                group.users.push(username.to_string());
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Backend for Memory {
+    fn quiet(&self) -> bool {
+        self.quiet
+    }
+
+    fn add_group(&mut self, group: &Group) -> io::Result<()> {
+        // Like `groupadd --force`, an existing group is a success.
+        if self.group(&group.name).is_ok() {
+            return Ok(());
+        }
+
+        let gid = match group.gid {
+            Some(gid) => gid,
+            None => {
+                let ids: Vec<(String, u32)> = self
+                    .groups
+                    .iter()
+                    .map(|g| (g.name.clone(), g.gid))
+                    .collect();
+                self.filter.next_gid(&ids)?
+            }
+        };
+        self.groups.push(SystemGroup {
+            name: group.name.clone(),
+            gid,
+            users: group.members.clone().unwrap_or_default(),
+        });
+
+        Ok(())
+    }
+
+    fn modify_group(&mut self, group: &Group, change: &GroupChange) -> io::Result<()> {
+        match change {
+            GroupChange::Gid { to, .. } => {
+                let from = self.group(&group.name)?.gid;
+
+                // The primary group of the users follows the group.
+                for user in self.users.iter_mut().filter(|u| u.gid == from) {
+                    user.gid = *to;
+                }
+                for system_group in self.groups.iter_mut().filter(|g| g.name == group.name) {
+                    system_group.gid = *to;
+                }
+            }
+            GroupChange::Members { add, remove } => {
+                self.group(&group.name)?;
+                // The members may be outside of the handled users (e.g. `www-data`),
+                // so their existence isn't checked.
+                for system_group in self.groups.iter_mut().filter(|g| g.name == group.name) {
+                    system_group.users.retain(|u| !remove.contains(u));
+                    system_group.users.extend(add.iter().cloned());
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn delete_group(&mut self, group: &SystemGroup) -> io::Result<()> {
+        self.group(&group.name)?;
+        self.groups.retain(|g| g.name != group.name);
+
+        Ok(())
+    }
+
+    fn add_user(&mut self, user: &User, _home: &HomePolicy) -> io::Result<()> {
+        if self.users.iter().any(|u| u.username == user.username) {
+            return Err(operation_error(format!(
+                "user '{}' already exists",
+                user.username
+            )));
+        }
+
+        let gid = self.group(&user.groups[0])?.gid;
+        for name in &user.groups[1..] {
+            self.group(name)?;
+        }
+
+        let mut aging = ShadowAging::default();
+        for (field, value) in user.aging_fields() {
+            aging.set(field, value);
+        }
+        let mut shell = user.shell.clone().unwrap_or(s!(consts::DEFAULT_SHELL));
+        if user.state == AccountState::Disabled {
+            aging.expire = Some(consts::DISABLED_EXPIRE);
+            shell = s!(consts::NOLOGIN_SHELL);
+        }
+
+        let uid = match user.uid {
+            Some(uid) => uid,
+            None => {
+                let ids: Vec<(String, u32)> = self
+                    .users
+                    .iter()
+                    .map(|u| (u.username.clone(), u.uid))
+                    .collect();
+                self.filter.next_uid(&ids)?
+            }
+        };
+        self.users.push(SystemUser {
+            username: user.username.clone(),
+            hash: s!("!"),
+            uid,
+            gid,
+            gecos: user.gecos.clone().unwrap_or_default(),
+            home: user
+                .home
+                .clone()
+                .unwrap_or_else(|| format!("{}/{}", consts::HOME_BASE_DIR, user.username)),
+            shell,
+            aging,
+            authorized_keys: AuthorizedKeys::default(),
+        });
+
+        self.set_membership(&user.username, &user.groups[1..])
+    }
+
+    fn modify_user(&mut self, user: &User, change: &UserChange) -> io::Result<()> {
+        match change {
+            UserChange::PrimaryGroup { to, .. } => {
+                let gid = self.group(to)?.gid;
+                self.user_mut(&user.username)?.gid = gid;
+            }
+            UserChange::SupplementaryGroups { add, remove } => {
+                self.user_mut(&user.username)?;
+                let mut groups: Vec<String> = self
+                    .groups
+                    .iter()
+                    .filter(|g| g.users.contains(&user.username) && !remove.contains(&g.name))
+                    .map(|g| g.name.clone())
+                    .collect();
+                groups.extend(add.iter().cloned());
+                self.set_membership(&user.username, &groups)?;
+            }
+            UserChange::Uid { to, .. } => self.user_mut(&user.username)?.uid = *to,
+            UserChange::Gecos { to, .. } => self.user_mut(&user.username)?.gecos = to.clone(),
+            UserChange::Home { to, .. } => self.user_mut(&user.username)?.home = to.clone(),
+            UserChange::Shell { to, .. } => self.user_mut(&user.username)?.shell = to.clone(),
+            UserChange::Aging { field, to, .. } => {
+                self.user_mut(&user.username)?.aging.set(*field, *to)
+            }
+            UserChange::State { from, to } => {
+                let system_user = self.user_mut(&user.username)?;
+                match (*from == AccountState::Active, *to == AccountState::Active) {
+                    (true, false) => system_user.hash.insert(0, '!'),
+                    (false, true) => {
+                        // Like `usermod --unlock`, an empty password can't be unlocked.
+                        if system_user.hash.len() <= 1 {
+                            return Err(operation_error(format!(
+                                "unlocking the password of '{}' would result in a passwordless account",
+                                user.username
+                            )));
+                        }
+                        system_user.hash.remove(0);
+                    }
+                    _ => {}
+                }
+
+                if *to == AccountState::Disabled {
+                    system_user.aging.expire = Some(consts::DISABLED_EXPIRE);
+                    system_user.shell = s!(consts::NOLOGIN_SHELL);
+                } else if *from == AccountState::Disabled {
+                    system_user.aging.expire = user.aging.expire_days();
+                    system_user.shell = user.shell.clone().unwrap_or(s!(consts::DEFAULT_SHELL));
+                }
+            }
+            UserChange::SshKeys { .. } => {
+                let system_user = self.user_mut(&user.username)?;
+                let content = system_user.authorized_keys.render(
+                    &user.ssh_key_lines().unwrap_or_default(),
+                    user.ssh_keys_mode,
+                );
+                system_user.authorized_keys = AuthorizedKeys::parse(&content);
+            }
+            // Passwords are applied all at once by `set_passwords`.
+            UserChange::Password => {}
+        }
+
+        Ok(())
+    }
+
+    fn delete_user(&mut self, user: &SystemUser, _home: &HomePolicy) -> io::Result<()> {
+        self.user_mut(&user.username)?;
+        self.users.retain(|u| u.username != user.username);
+        for group in self.groups.iter_mut() {
+            group.users.retain(|u| u != &user.username);
+        }
+
+        Ok(())
+    }
+
+    /// Plain text passwords are stored as is since nothing is written to the system.
+    fn set_passwords(&mut self, users: &[User], _encrypted: bool) -> io::Result<()> {
+        let mut errors = Vec::<String>::new();
+        for user in users {
+            match self.user_mut(&user.username) {
+                Ok(system_user) => system_user.hash = user.password.clone(),
+                Err(e) => errors.push(e.to_string()),
+            }
+        }
+
+        match errors.is_empty() {
+            true => Ok(()),
+            false => Err(operation_error(errors.join("\n"))),
+        }
+    }
+
+    fn set_sudoers(&mut self, _change: &SudoersChange) -> io::Result<()> {
+        // The drop-in is outside of the user database.
+        Ok(())
+    }
+}
@@ -0,0 +1,162 @@
+mod files;
+mod memory;
+mod shadow_utils;
+
+use std::{fs, io, os::unix::fs::PermissionsExt, path::Path};
+
+use serde::Serialize;
+
+use crate::{
+    differ::{GroupChange, SudoersChange, UserChange},
+    sync_structs::{Group, HomePolicy, User},
+    system_parser::{SystemGroup, SystemUser},
+};
+
+pub use files::Files;
+pub use memory::Memory;
+pub use shadow_utils::ShadowUtils;
+
+/**
+A system user database which can be modified by the runner.
+
+Every operation returns an error when the change could not be applied,
+the runner is responsible to report it and carry on with the next operation.
+ */
+pub trait Backend {
+    /**
+    Check if the backend only prints the changes instead of applying them.
+
+    # Returns
+    `true` when the backend doesn't modify anything.
+     */
+    fn dry_run(&self) -> bool {
+        false
+    }
+
+    /**
+    Check if the progress of the operations is printed.
+
+    # Returns
+    `true` when nothing is printed (e.g. with the JSON output).
+     */
+    fn quiet(&self) -> bool {
+        false
+    }
+
+    /**
+    Take the commands run since the last call, in dry-run they are recorded without being run.
+
+    # Returns
+    The commands, empty for the backends which don't run any.
+     */
+    fn take_commands(&mut self) -> Vec<CommandRun> {
+        Vec::new()
+    }
+
+    /**
+    Add a new group.
+
+    # Parameters
+    * `group`: The group, as defined in the sync file.
+     */
+    fn add_group(&mut self, group: &Group) -> io::Result<()>;
+
+    /**
+    Apply a single change to an existing group.
+
+    # Parameters
+    * `group`: The group, as defined in the sync file.
+    * `change`: The change to apply.
+     */
+    fn modify_group(&mut self, group: &Group, change: &GroupChange) -> io::Result<()>;
+
+    /**
+    Delete a group.
+
+    # Parameters
+    * `group`: The system group to delete.
+     */
+    fn delete_group(&mut self, group: &SystemGroup) -> io::Result<()>;
+
+    /**
+    Add a new user.
+
+    # Parameters
+    * `user`: The user, as defined in the sync file. It must have at least one group.
+    * `home`: How the home directory of the user is created.
+     */
+    fn add_user(&mut self, user: &User, home: &HomePolicy) -> io::Result<()>;
+
+    /**
+    Apply a single change to an existing user.
+
+    # Parameters
+    * `user`: The user, as defined in the sync file.
+    * `change`: The change to apply.
+     */
+    fn modify_user(&mut self, user: &User, change: &UserChange) -> io::Result<()>;
+
+    /**
+    Delete a user.
+
+    # Parameters
+    * `user`: The system user to delete.
+    * `home`: What is done with the home directory of the user.
+     */
+    fn delete_user(&mut self, user: &SystemUser, home: &HomePolicy) -> io::Result<()>;
+
+    /**
+    Set the password of a list of users.
+
+    # Parameters
+    * `users`: The users, as defined in the sync file.
+    * `encrypted`: `true` when the passwords are already hashed.
+     */
+    fn set_passwords(&mut self, users: &[User], encrypted: bool) -> io::Result<()>;
+
+    /**
+    Write or remove the sudoers drop-in.
+
+    # Parameters
+    * `change`: The change of the drop-in.
+     */
+    fn set_sudoers(&mut self, change: &SudoersChange) -> io::Result<()>;
+}
+
+/**
+A command run to apply an operation.
+ */
+#[derive(Debug, Clone, Serialize)]
+pub struct CommandRun {
+    pub command: String,
+    /// The arguments, the standard input (e.g. the passwords) is never recorded.
+    pub args: Vec<String>,
+    /// The exit code, `None` when the command wasn't run (dry-run), couldn't be spawned or was killed.
+    pub status: Option<i32>,
+    pub stderr: String,
+}
+
+/**
+Create an `io::Error` for an operation which couldn't be applied.
+
+# Parameters
+* `message`: The description of the error.
+ */
+pub fn operation_error(message: String) -> io::Error {
+    io::Error::other(message)
+}
+
+/**
+Create the directory of home archives, only a directory created here is restricted to `root`,
+the permissions of an existing directory are left as configured by the administrator.
+
+# Parameters
+* `dir`: The directory receiving the archives.
+ */
+pub fn create_archive_dir(dir: &Path) -> io::Result<()> {
+    if dir.is_dir() {
+        return Ok(());
+    }
+    fs::create_dir_all(dir)?;
+    fs::set_permissions(dir, fs::Permissions::from_mode(0o700))
+}
@@ -0,0 +1,573 @@
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output, Stdio};
+
+use super::{create_archive_dir, operation_error, Backend, CommandRun};
+use crate::consts::{
+    self, ADD_GROUP_CMD, ADD_USER_CMD, ARCHIVE_CMD, REMOVE_GROUP_CMD, REMOVE_USER_CMD,
+    UPDATE_AGING_CMD, UPDATE_GROUP_CMD, UPDATE_MEMBERS_CMD, UPDATE_PASSWORDS_CMD, UPDATE_USER_CMD,
+};
+use crate::{date, s, ssh, sudo};
+use crate::{
+    differ::{GroupChange, SudoersChange, UserChange},
+    sync_structs::{AccountState, Group, HomePolicy, HomeRemoval, User},
+    system_parser::{self, AgingField, SystemGroup, SystemUser},
+};
+
+/**
+Backend applying the changes with the shadow-utils commands (`useradd`, `usermod`, ...).
+ */
+#[derive(Debug, Default)]
+pub struct ShadowUtils {
+    /// When `true`, the commands are printed instead of being executed.
+    pub dry_run: bool,
+    /// When `true`, the commands aren't printed in dry-run, they are only recorded.
+    pub quiet: bool,
+    /// The root of the filesystem to modify, `None` for the live system.
+    pub root: Option<PathBuf>,
+    /// The commands run since they were last taken.
+    commands: Vec<CommandRun>,
+}
+
+impl ShadowUtils {
+    /**
+    Create a new shadow-utils backend.
+
+    # Parameters
+    * `dry_run`: When `true`, the commands are printed instead of being executed.
+    * `quiet`: When `true`, nothing is printed.
+    * `root`: The root of the filesystem to modify (`/` for the live system).
+     */
+    pub fn new(dry_run: bool, quiet: bool, root: &Path) -> Self {
+        Self {
+            dry_run,
+            quiet,
+            root: match root == Path::new("/") {
+                true => None,
+                false => Some(root.to_path_buf()),
+            },
+            commands: vec![],
+        }
+    }
+
+    /**
+    Run the specified command with given arguments and stdin buffer.
+    When a root is set, the `--prefix` option (`--root` for `chage`, `gpasswd` and `chpasswd` which don't support it)
+    is given to the shadow-utils commands so the files under the root are modified instead of the live system ones.
+
+    # Parameters
+    * `command`: A string representation of the command to run.
+    * `args`: A list of arguments to be passed to the command.
+    * `stdin_buf`: The standard input to be written once the command is spawned.
+
+    # Returns
+    An error when the command couldn't be spawned or exited with a failure status.
+     */
+    fn run_command(
+        &mut self,
+        command: &str,
+        args: &[&str],
+        stdin_buf: Option<&str>,
+    ) -> io::Result<()> {
+        let root = self
+            .root
+            .as_ref()
+            .map(|root| root.to_string_lossy().into_owned());
+        let mut prefixed_args: Vec<&str> = vec![];
+        if let Some(root) = &root {
+            let option = match command {
+                UPDATE_AGING_CMD | UPDATE_MEMBERS_CMD | UPDATE_PASSWORDS_CMD => Some("--root"),
+                // The paths given to `tar` already include the root.
+                ARCHIVE_CMD => None,
+                _ => Some("--prefix"),
+            };
+            if let Some(option) = option {
+                prefixed_args.push(option);
+                prefixed_args.push(root);
+            }
+        }
+        prefixed_args.extend_from_slice(args);
+        let args = &prefixed_args[..];
+
+        if self.dry_run {
+            if !self.quiet {
+                println!("Skipped (dry-run)");
+                println!("\t{}", render_command(command, args));
+                if let Some(stdin_buf) = stdin_buf {
+                    // Never print the passwords, only the name of the entries.
+                    for line in stdin_buf.lines() {
+                        let name = line.split(':').next().unwrap_or_default();
+                        println!("\t\t< {}:********", name);
+                    }
+                }
+            }
+            self.record(command, args, None, "");
+            return Ok(());
+        }
+
+        let output = match execute(command, args, stdin_buf) {
+            Ok(output) => output,
+            Err(e) => {
+                self.record(command, args, None, &e.to_string());
+                return Err(e);
+            }
+        };
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        let stderr = stderr.trim_end();
+        self.record(command, args, output.status.code(), stderr);
+
+        match output.status.success() {
+            true => Ok(()),
+            false => Err(operation_error(format!(
+                "{} ({})\n{}",
+                render_command(command, args),
+                output.status,
+                stderr
+            ))),
+        }
+    }
+
+    /**
+    Record a command run to apply an operation.
+
+    # Parameters
+    * `command`: The command.
+    * `args`: The arguments of the command.
+    * `status`: The exit code, `None` when the command wasn't run or was killed.
+    * `stderr`: The error output of the command.
+     */
+    fn record(&mut self, command: &str, args: &[&str], status: Option<i32>, stderr: &str) {
+        self.commands.push(CommandRun {
+            command: s!(command),
+            args: args.iter().map(|arg| s!(*arg)).collect(),
+            status,
+            stderr: s!(stderr),
+        });
+    }
+}
+
+impl ShadowUtils {
+    /**
+    Archive the home directory of a user in a tarball, before it is removed.
+
+    # Parameters
+    * `user`: The system user.
+    * `archive`: The absolute path of the archive on the system.
+     */
+    fn archive_home(&mut self, user: &SystemUser, archive: &str) -> io::Result<()> {
+        let root = self.root.as_deref().unwrap_or_else(|| Path::new("/"));
+        let home = system_parser::system_path(root, &user.home);
+        let archive = system_parser::system_path(root, archive);
+        // Nothing to archive, `userdel` only warns about the missing directory.
+        if !home.is_dir() {
+            return Ok(());
+        }
+
+        let (Some(parent), Some(name)) = (home.parent(), home.file_name()) else {
+            return Err(operation_error(format!(
+                "can't archive the home directory {}",
+                home.display()
+            )));
+        };
+        if !self.dry_run {
+            if let Some(dir) = archive.parent() {
+                create_archive_dir(dir)?;
+            }
+        }
+
+        self.run_command(
+            ARCHIVE_CMD,
+            &[
+                "-czf",
+                &archive.to_string_lossy(),
+                "-C",
+                &parent.to_string_lossy(),
+                &name.to_string_lossy(),
+            ],
+            None,
+        )
+    }
+}
+
+impl Backend for ShadowUtils {
+    fn dry_run(&self) -> bool {
+        self.dry_run
+    }
+
+    fn quiet(&self) -> bool {
+        self.quiet
+    }
+
+    fn take_commands(&mut self) -> Vec<CommandRun> {
+        std::mem::take(&mut self.commands)
+    }
+
+    fn add_group(&mut self, group: &Group) -> io::Result<()> {
+        // Compose options
+        let mut args: Vec<String> = vec![s!("--force")];
+        if let Some(gid) = group.gid {
+            args.push(s!("--non-unique"));
+            args.push(s!("--gid"));
+            args.push(gid.to_string());
+        }
+        // The synchronized users are added by `useradd`.
+        if let Some(members) = group.members.as_ref().filter(|m| !m.is_empty()) {
+            args.push(s!("--users"));
+            args.push(members.join(","));
+        }
+
+        args.push(group.name.clone());
+
+        let args: Vec<&str> = args.iter().map(|a| a.as_ref()).collect();
+        self.run_command(ADD_GROUP_CMD, &args, None)
+    }
+
+    fn modify_group(&mut self, group: &Group, change: &GroupChange) -> io::Result<()> {
+        match change {
+            GroupChange::Gid { to, .. } => self.run_command(
+                UPDATE_GROUP_CMD,
+                &["--non-unique", "--gid", &to.to_string(), &group.name],
+                None,
+            ),
+            GroupChange::Members { add, remove } => {
+                for member in add {
+                    self.run_command(UPDATE_MEMBERS_CMD, &["--add", member, &group.name], None)?;
+                }
+                for member in remove {
+                    self.run_command(UPDATE_MEMBERS_CMD, &["--delete", member, &group.name], None)?;
+                }
+
+                Ok(())
+            }
+        }
+    }
+
+    fn delete_group(&mut self, group: &SystemGroup) -> io::Result<()> {
+        self.run_command(REMOVE_GROUP_CMD, &["-f", &group.name], None)
+    }
+
+    fn add_user(&mut self, user: &User, home: &HomePolicy) -> io::Result<()> {
+        // Compose arguments.
+        let mut args: Vec<String> = vec![s!("--create-home"), s!("--no-user-group")];
+
+        // Home directory creation, the defaults of `/etc/login.defs` are used when unset.
+        if let Some(skel) = &home.skel {
+            args.push(s!("--skel"));
+            args.push(skel.clone());
+        }
+        if let Some(mode) = home.mode() {
+            args.push(s!("--key"));
+            args.push(format!("HOME_MODE={:04o}", mode));
+        }
+
+        // Primary group.
+        let primary = &user.groups[0];
+        args.push(s!("--gid"));
+        args.push(primary.clone());
+
+        // Supplementary groups.
+        if user.groups.len() > 1 {
+            let groups_str = user.groups[1..].join(",");
+            args.push(s!("--groups"));
+            args.push(groups_str);
+        }
+
+        // UID
+        if let Some(uid) = user.uid {
+            args.push(s!("--non-unique"));
+            args.push(s!("--uid"));
+            args.push(uid.to_string());
+        }
+
+        // GECOS, home directory and login shell, the system defaults are used when unset.
+        if let Some(gecos) = &user.gecos {
+            args.push(s!("--comment"));
+            args.push(gecos.clone());
+        }
+        if let Some(home) = &user.home {
+            args.push(s!("--home-dir"));
+            args.push(home.clone());
+        }
+        if let Some(shell) = &user.shell {
+            args.push(s!("--shell"));
+            args.push(shell.clone());
+        }
+
+        // A new account is created with a locked password, a disabled one is also expired.
+        if user.state == AccountState::Disabled {
+            args.push(s!("--expiredate"));
+            args.push(date::format_date(consts::DISABLED_EXPIRE));
+            args.push(s!("--shell"));
+            args.push(s!(consts::NOLOGIN_SHELL));
+        }
+
+        args.push(s!(user.username.clone()));
+
+        let args: Vec<&str> = args.iter().map(|a| a.as_ref()).collect();
+        self.run_command(ADD_USER_CMD, &args, None)?;
+
+        // Password aging, `useradd` only knows the defaults of `/etc/login.defs`.
+        let fields = user.aging_fields();
+        if fields.is_empty() {
+            return Ok(());
+        }
+
+        let mut args: Vec<String> = vec![];
+        for (field, value) in fields {
+            let (flag, value) = aging_args(field, value);
+            args.push(s!(flag));
+            args.push(value);
+        }
+        args.push(user.username.clone());
+
+        let args: Vec<&str> = args.iter().map(|a| a.as_ref()).collect();
+        self.run_command(UPDATE_AGING_CMD, &args, None)
+    }
+
+    fn modify_user(&mut self, user: &User, change: &UserChange) -> io::Result<()> {
+        match change {
+            UserChange::PrimaryGroup { to, .. } => {
+                self.run_command(UPDATE_USER_CMD, &["--gid", to, &user.username], None)
+            }
+            UserChange::SupplementaryGroups { add, remove } => {
+                // Only the changed memberships are applied, the others are left untouched.
+                if !add.is_empty() {
+                    self.run_command(
+                        UPDATE_USER_CMD,
+                        &["--append", "--groups", &add.join(","), &user.username],
+                        None,
+                    )?;
+                }
+                for group in remove {
+                    self.run_command(
+                        UPDATE_MEMBERS_CMD,
+                        &["--delete", &user.username, group],
+                        None,
+                    )?;
+                }
+
+                Ok(())
+            }
+            UserChange::Uid { to, .. } => self.run_command(
+                UPDATE_USER_CMD,
+                &["--non-unique", "--uid", &to.to_string(), &user.username],
+                None,
+            ),
+            UserChange::Gecos { to, .. } => {
+                self.run_command(UPDATE_USER_CMD, &["--comment", to, &user.username], None)
+            }
+            // The content of the current home directory is moved to the new one.
+            UserChange::Home { to, .. } => self.run_command(
+                UPDATE_USER_CMD,
+                &["--home", to, "--move-home", &user.username],
+                None,
+            ),
+            UserChange::Shell { to, .. } => {
+                self.run_command(UPDATE_USER_CMD, &["--shell", to, &user.username], None)
+            }
+            UserChange::Aging { field, to, .. } => {
+                let (flag, value) = aging_args(*field, *to);
+                self.run_command(UPDATE_AGING_CMD, &[flag, &value, &user.username], None)
+            }
+            UserChange::State { from, to } => {
+                let mut args: Vec<String> = vec![];
+                match (*from == AccountState::Active, *to == AccountState::Active) {
+                    (true, false) => args.push(s!("--lock")),
+                    (false, true) => args.push(s!("--unlock")),
+                    _ => {}
+                }
+
+                // Disabling expires the account and prevents the login,
+                // the expiry date and shell of the sync file are restored on enabling.
+                if *to == AccountState::Disabled {
+                    args.push(s!("--expiredate"));
+                    args.push(date::format_date(consts::DISABLED_EXPIRE));
+                    args.push(s!("--shell"));
+                    args.push(s!(consts::NOLOGIN_SHELL));
+                } else if *from == AccountState::Disabled {
+                    let expire = user.aging.expire_days();
+                    args.push(s!("--expiredate"));
+                    args.push(aging_args(AgingField::Expire, expire).1);
+                    args.push(s!("--shell"));
+                    args.push(user.shell.clone().unwrap_or(s!(consts::DEFAULT_SHELL)));
+                }
+
+                args.push(user.username.clone());
+                let args: Vec<&str> = args.iter().map(|a| a.as_ref()).collect();
+                self.run_command(UPDATE_USER_CMD, &args, None)
+            }
+            // There's no shadow-utils command for the SSH keys, the file is written directly.
+            UserChange::SshKeys { .. } => {
+                if self.dry_run {
+                    if !self.quiet {
+                        println!("Skipped (dry-run)");
+                        println!(
+                            "\twrite ~{}/{}",
+                            user.username,
+                            consts::AUTHORIZED_KEYS_FILE
+                        );
+                    }
+                    return Ok(());
+                }
+
+                let root = self.root.as_deref().unwrap_or_else(|| Path::new("/"));
+                ssh::write_authorized_keys(
+                    root,
+                    &user.username,
+                    &user.ssh_key_lines().unwrap_or_default(),
+                    user.ssh_keys_mode,
+                )
+            }
+            // Passwords are applied all at once by `set_passwords`.
+            UserChange::Password => Ok(()),
+        }
+    }
+
+    fn delete_user(&mut self, user: &SystemUser, home: &HomePolicy) -> io::Result<()> {
+        let removal = home.remove.unwrap_or_default();
+        if removal == HomeRemoval::Archive {
+            self.archive_home(user, &home.archive_file(&user.username))?;
+        }
+
+        match removal {
+            HomeRemoval::Keep => self.run_command(REMOVE_USER_CMD, &[&user.username], None),
+            HomeRemoval::Delete | HomeRemoval::Archive => {
+                self.run_command(REMOVE_USER_CMD, &["--remove", &user.username], None)
+            }
+        }
+    }
+
+    fn set_passwords(&mut self, users: &[User], encrypted: bool) -> io::Result<()> {
+        let mut stdin_buf = String::new();
+        for user in users {
+            stdin_buf.push_str(&format!("{}:{}\n", &user.username, &user.password))
+        }
+
+        // The method is forced so the hash can be verified by the next diff,
+        // the default of recent distributions (yescrypt) isn't supported by `pwhash`.
+        let args: Vec<&str> = match encrypted {
+            true => vec!["--encrypted"],
+            false => vec!["--crypt-method", "SHA512"],
+        };
+
+        self.run_command(UPDATE_PASSWORDS_CMD, &args, Some(&stdin_buf))
+    }
+
+    fn set_sudoers(&mut self, change: &SudoersChange) -> io::Result<()> {
+        if self.dry_run {
+            if !self.quiet {
+                println!("Skipped (dry-run)");
+                match change {
+                    SudoersChange::Write(_) => println!("\twrite {}", consts::SUDOERS_FILE),
+                    SudoersChange::Remove => println!("\tremove {}", consts::SUDOERS_FILE),
+                }
+            }
+            return Ok(());
+        }
+
+        let content = match change {
+            SudoersChange::Write(content) => Some(content.as_str()),
+            SudoersChange::Remove => None,
+        };
+        sudo::install(
+            self.root.as_deref().unwrap_or_else(|| Path::new("/")),
+            content,
+        )
+    }
+}
+
+/**
+Get the `chage` option and value setting a password aging field.
+
+# Parameters
+* `field`: The aging field.
+* `value`: The value of the field, `None` to disable the feature.
+
+# Returns
+A tuple with the option (0) and its value (1), `-1` disables the feature.
+ */
+fn aging_args(field: AgingField, value: Option<i64>) -> (&'static str, String) {
+    let flag = match field {
+        AgingField::Minimum => "--mindays",
+        AgingField::Maximum => "--maxdays",
+        AgingField::Warn => "--warndays",
+        AgingField::Inactive => "--inactive",
+        AgingField::Expire => "--expiredate",
+    };
+    let value = match (field, value) {
+        (AgingField::Expire, Some(days)) => date::format_date(days),
+        (_, Some(days)) => days.to_string(),
+        (_, None) => s!("-1"),
+    };
+
+    (flag, value)
+}
+
+/**
+Spawn a command and wait for it.
+
+# Parameters
+* `command`: The command to run.
+* `args`: The arguments of the command.
+* `stdin_buf`: The standard input to be written once the command is spawned.
+
+# Returns
+The output of the command, or an error when it couldn't be spawned.
+ */
+fn execute(command: &str, args: &[&str], stdin_buf: Option<&str>) -> io::Result<Output> {
+    let mut cmd = Command::new(command);
+    cmd.args(args).stdout(Stdio::piped()).stderr(Stdio::piped());
+
+    // Only pipe stdin when needed.
+    if stdin_buf.is_some() {
+        cmd.stdin(Stdio::piped());
+    }
+
+    let mut child = cmd.spawn()?;
+
+    // Write to stdin when a buffer is present.
+    if let Some(stdin_buf) = stdin_buf {
+        if let Some(mut stdin) = child.stdin.take() {
+            let write_rst = stdin
+                .write_all(stdin_buf.as_bytes())
+                .and_then(|_| stdin.flush());
+
+            if let Err(e) = write_rst {
+                child.kill()?;
+                return Err(e);
+            }
+        }
+    }
+
+    // Wait for command to finish.
+    child.wait_with_output()
+}
+
+/**
+Render a command and its arguments as a shell command line.
+Arguments containing special characters are single-quoted.
+
+# Parameters
+* `command`: A string representation of the command.
+* `args`: The list of arguments passed to the command.
+
+# Returns
+A `String` with the rendered command line.
+ */
+fn render_command(command: &str, args: &[&str]) -> String {
+    let mut line = s!(command);
+    for arg in args {
+        line.push(' ');
+        let plain = !arg.is_empty()
+            && arg
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || "-_.,/=:@%+".contains(c));
+        if plain {
+            line.push_str(arg);
+        } else {
+            line.push_str(&format!("'{}'", arg.replace('\'', "'\\''")));
+        }
+    }
+
+    line
+}
@@ -0,0 +1,267 @@
+use std::{env, path::PathBuf, time::Duration};
+
+use crate::{s, sync_structs::Format};
+
+/**
+The backend used to apply the changes.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum BackendKind {
+    /// The shadow-utils commands (`useradd`, `usermod`, ...).
+    #[default]
+    ShadowUtils,
+    /// Edit the user database files directly.
+    Files,
+    /// An in-memory copy of the system database, nothing is written.
+    Memory,
+}
+
+/**
+The format of the output of the application.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum OutputFormat {
+    /// Messages describing the progress of the operations.
+    #[default]
+    Text,
+    /// A single JSON document with the plan and the result of every operation.
+    Json,
+}
+
+/**
+The command run by the application.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Command {
+    /// Synchronize the system with the sync file.
+    #[default]
+    Sync,
+    /// Only validate the sync file.
+    Validate,
+    /// Compare the system with the sync file without applying anything.
+    Check,
+    /// Restore the user database saved before the last sync.
+    Rollback,
+    /// Synchronize every time the sync file changes.
+    Watch,
+}
+
+impl Command {
+    /**
+    Get a command from its name.
+
+    # Parameters
+    * `name`: The name of the command.
+
+    # Returns
+    The command, `None` when the name is unknown.
+     */
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "sync" => Some(Self::Sync),
+            "validate" => Some(Self::Validate),
+            "check" => Some(Self::Check),
+            "rollback" => Some(Self::Rollback),
+            "watch" => Some(Self::Watch),
+            _ => None,
+        }
+    }
+}
+
+/**
+The options given to the application on the command line.
+ */
+#[derive(Debug)]
+pub struct Options {
+    /// The command to run.
+    pub command: Command,
+    /// The sync file path given as positional argument, if any.
+    pub sync_path: Option<PathBuf>,
+    /// The format of the sync file, guessed from the extension when `None`.
+    pub format: Option<Format>,
+    /// Only print the commands that would be executed.
+    pub dry_run: bool,
+    /// The backend used to apply the changes.
+    pub backend: BackendKind,
+    /// The root of the filesystem to synchronize.
+    pub root: PathBuf,
+    /// Remove every unknown user and group, not only the managed ones.
+    pub exhaustive: bool,
+    /// Keep the applied changes when an operation fails instead of rolling back.
+    pub no_rollback: bool,
+    /// Manage the users and groups of the sync file which already exist on the system.
+    pub adopt: bool,
+    /// The format of the output.
+    pub output: OutputFormat,
+    /// The delay between two synchronizations of the `watch` command, even when the sync file didn't change.
+    pub interval: Option<Duration>,
+    /// Print the usage and exit.
+    pub help: bool,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            command: Command::default(),
+            sync_path: None,
+            format: None,
+            dry_run: false,
+            backend: BackendKind::default(),
+            root: PathBuf::from("/"),
+            exhaustive: false,
+            no_rollback: false,
+            adopt: false,
+            output: OutputFormat::default(),
+            interval: None,
+            help: false,
+        }
+    }
+}
+
+impl Options {
+    /**
+    Parse the options from the arguments of the application.
+
+    # Returns
+    The parsed `Options`, or a `String` describing the invalid argument.
+     */
+    pub fn parse() -> Result<Self, String> {
+        Self::parse_from(env::args().skip(1))
+    }
+
+    /**
+    Parse the options from a list of arguments (without the program name).
+
+    # Parameters
+    * `args`: The arguments to parse.
+
+    # Returns
+    The parsed `Options`, or a `String` describing the invalid argument.
+     */
+    pub fn parse_from<I: Iterator<Item = String>>(args: I) -> Result<Self, String> {
+        let mut options = Self::default();
+
+        let mut args = args.peekable();
+
+        // The command is the first argument, when given.
+        if let Some(command) = args.peek().and_then(|arg| Command::from_name(arg)) {
+            options.command = command;
+            args.next();
+        }
+
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--dry-run" | "--plan" => options.dry_run = true,
+                "--backend" => {
+                    options.backend = match next_value(&mut args, &arg)?.as_str() {
+                        "shadow-utils" => BackendKind::ShadowUtils,
+                        "files" => BackendKind::Files,
+                        "memory" => BackendKind::Memory,
+                        value => return Err(format!("Unknown backend `{}`", value)),
+                    }
+                }
+                "--format" => {
+                    let value = next_value(&mut args, &arg)?;
+                    options.format = Some(
+                        Format::from_name(&value)
+                            .ok_or_else(|| format!("Unknown format `{}`", value))?,
+                    );
+                }
+                "--root" => options.root = PathBuf::from(next_value(&mut args, &arg)?),
+                "--exhaustive" => options.exhaustive = true,
+                "--no-rollback" => options.no_rollback = true,
+                "--adopt" => options.adopt = true,
+                "--output" => {
+                    options.output = match next_value(&mut args, &arg)?.as_str() {
+                        "text" => OutputFormat::Text,
+                        "json" => OutputFormat::Json,
+                        value => return Err(format!("Unknown output format `{}`", value)),
+                    }
+                }
+                "--interval" => {
+                    let value = next_value(&mut args, &arg)?;
+                    options.interval = match value.parse::<u64>() {
+                        Ok(seconds) if seconds > 0 => Some(Duration::from_secs(seconds)),
+                        _ => return Err(format!("Invalid interval `{}`", value)),
+                    };
+                }
+                "-h" | "--help" => options.help = true,
+                flag if flag.starts_with("--") => {
+                    return Err(format!("Unknown option `{}`", flag));
+                }
+                _ => {
+                    if options.sync_path.is_some() {
+                        return Err(format!("Unexpected argument `{}`", arg));
+                    }
+                    options.sync_path = Some(PathBuf::from(arg));
+                }
+            }
+        }
+
+        if options.interval.is_some() && options.command != Command::Watch {
+            return Err(s!(
+                "The option `--interval` is only used by the `watch` command"
+            ));
+        }
+        if options.output == OutputFormat::Json
+            && matches!(options.command, Command::Rollback | Command::Watch)
+        {
+            return Err(s!(
+                "The JSON output is only supported by the `sync`, `validate` and `check` commands"
+            ));
+        }
+
+        Ok(options)
+    }
+}
+
+/**
+Get the value of an option.
+
+# Parameters
+* `args`: The remaining arguments.
+* `option`: The name of the option, used in the error message.
+
+# Returns
+The next argument, or an error when there's none.
+ */
+fn next_value<I: Iterator<Item = String>>(args: &mut I, option: &str) -> Result<String, String> {
+    args.next()
+        .ok_or_else(|| format!("Missing value for option `{}`", option))
+}
+
+/**
+Get the usage message of the application.
+ */
+pub fn usage() -> String {
+    s!("Usage: user-sync [COMMAND] [OPTIONS] [SYNC_FILE]
+
+Commands:
+  sync               Synchronize the system with the sync file (default).
+  validate           Validate the sync file without reading or modifying the system.
+  check              Exit with code 14 when the system differs from the sync file,
+                     nothing is applied.
+  rollback           Restore the user database saved before the last sync.
+  watch              Synchronize, then synchronize again every time the sync file changes.
+
+Options:
+  --dry-run, --plan  Print the commands that would be executed without running them.
+  --backend BACKEND  The backend used to apply the changes:
+                       shadow-utils  Run useradd, usermod, ... (default)
+                       files         Edit /etc/passwd, /etc/group, ... directly
+                       memory        Apply to an in-memory copy, nothing is written
+  --format FORMAT    The format of the sync file: json, yaml or toml
+                     (default: guessed from the extension, then json).
+  --root PATH        The root of the filesystem to synchronize (default: /).
+  --exhaustive       Remove every user and group not in the sync file,
+                     not only the ones managed by user-sync.
+  --no-rollback      Keep the applied changes when an operation fails.
+  --adopt            Also manage the users and groups of the sync file which
+                     already exist (e.g. created by hand), so they can be removed.
+  --output FORMAT    The format of the output: text (default) or json,
+                     a single JSON document with the plan and the result
+                     of every operation (sync, validate and check only).
+  --interval SECONDS With `watch`, also synchronize periodically even when
+                     the sync file didn't change (e.g. for LDAP sources).
+  -h, --help         Print this message.")
+}
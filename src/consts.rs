@@ -1,44 +1,82 @@
 pub const MIN_UID: u32 = 1000;
 pub const MIN_GID: u32 = 1000;
+pub const HOME_BASE_DIR: &str = "/home";
+pub const DEFAULT_SHELL: &str = "/bin/sh";
+pub const NOLOGIN_SHELL: &str = "/usr/sbin/nologin";
+/// The expiry date of the disabled accounts (1970-01-02), as recommended by `usermod --lock`.
+pub const DISABLED_EXPIRE: i64 = 1;
 
-pub const USER_FILE: &'static str = "/etc/passwd";
-pub const GROUP_FILE: &'static str = "/etc/group";
-pub const PASSWORD_FILE: &'static str = "/etc/shadow";
+pub const USER_FILE: &str = "/etc/passwd";
+pub const GROUP_FILE: &str = "/etc/group";
+pub const PASSWORD_FILE: &str = "/etc/shadow";
+pub const GROUP_PASSWORD_FILE: &str = "/etc/gshadow";
+/// The lock of the whole user database, as taken by `lckpwdf()`.
+pub const DATABASE_LOCK_FILE: &str = "/etc/.pwd.lock";
+pub const SKEL_DIR: &str = "/etc/skel";
+/// The SSH keys of a user, relative to their home directory.
+pub const AUTHORIZED_KEYS_FILE: &str = ".ssh/authorized_keys";
+pub const SUDOERS_FILE: &str = "/etc/sudoers.d/user-sync";
+pub const LOGIN_DEFS_FILE: &str = "/etc/login.defs";
+pub const STATE_FILE: &str = "/var/lib/user-sync/managed.json";
+pub const HOME_ARCHIVE_DIR: &str = "/var/lib/user-sync/archives";
+pub const SNAPSHOT_DIR: &str = "/var/lib/user-sync/snapshot";
 
-pub const NAME_FIELD: &'static str = "name";
-pub const PASSWORD_FIELD: &'static str = "password";
-pub const UID_FIELD: &'static str = "uid";
-pub const GID_FIELD: &'static str = "gid";
-pub const USER_LIST_FIELD: &'static str = "users";
+pub const NAME_FIELD: &str = "name";
+pub const PASSWORD_FIELD: &str = "password";
+pub const UID_FIELD: &str = "uid";
+pub const GID_FIELD: &str = "gid";
+pub const USER_LIST_FIELD: &str = "users";
+pub const GECOS_FIELD: &str = "gecos";
+pub const HOME_FIELD: &str = "home";
+pub const SHELL_FIELD: &str = "shell";
+pub const LAST_CHANGED_FIELD: &str = "last_changed";
+pub const MINIMUM_FIELD: &str = "minimum";
+pub const MAXIMUM_FIELD: &str = "maximum";
+pub const WARN_FIELD: &str = "warn";
+pub const INACTIVE_FIELD: &str = "inactive";
+pub const EXPIRE_FIELD: &str = "expire";
+pub const RESERVED_FIELD: &str = "reserved";
+pub const ADMIN_LIST_FIELD: &str = "admins";
 
-pub const USER_FIELDS: &'static [&'static str] = &[
+pub const USER_FIELDS: &[&str] = &[
     NAME_FIELD,
     PASSWORD_FIELD,
     UID_FIELD,
     GID_FIELD,
-    "gecos",
-    "home",
-    "shell",
+    GECOS_FIELD,
+    HOME_FIELD,
+    SHELL_FIELD,
 ];
-pub const GROUP_FIELDS: &'static [&'static str] =
-    &[NAME_FIELD, PASSWORD_FIELD, GID_FIELD, USER_LIST_FIELD];
-pub const PASSWORD_FIELDS: &'static [&'static str] = &[
+pub const GROUP_FIELDS: &[&str] = &[NAME_FIELD, PASSWORD_FIELD, GID_FIELD, USER_LIST_FIELD];
+pub const PASSWORD_FIELDS: &[&str] = &[
     NAME_FIELD,
     PASSWORD_FIELD,
-    "last_changed",
-    "minimum",
-    "maximum",
-    "warn",
-    "inactive",
-    "expire",
+    LAST_CHANGED_FIELD,
+    MINIMUM_FIELD,
+    MAXIMUM_FIELD,
+    WARN_FIELD,
+    INACTIVE_FIELD,
+    EXPIRE_FIELD,
+    RESERVED_FIELD,
 ];
+pub const GROUP_PASSWORD_FIELDS: &[&str] = &[
+    NAME_FIELD,
+    PASSWORD_FIELD,
+    ADMIN_LIST_FIELD,
+    USER_LIST_FIELD,
+];
+
+pub const ADD_GROUP_CMD: &str = "/usr/sbin/groupadd";
+pub const REMOVE_GROUP_CMD: &str = "/usr/sbin/groupdel";
+pub const UPDATE_GROUP_CMD: &str = "/usr/sbin/groupmod";
+pub const UPDATE_MEMBERS_CMD: &str = "/usr/bin/gpasswd";
 
-pub const ADD_GROUP_CMD: &'static str = "/usr/sbin/groupadd";
-pub const REMOVE_GROUP_CMD: &'static str = "/usr/sbin/groupdel";
-pub const UPDATE_GROUP_CMD: &'static str = "/usr/sbin/groupmod";
+pub const ADD_USER_CMD: &str = "/usr/sbin/useradd";
+pub const REMOVE_USER_CMD: &str = "/usr/sbin/userdel";
+pub const UPDATE_USER_CMD: &str = "/usr/sbin/usermod";
 
-pub const ADD_USER_CMD: &'static str = "/usr/sbin/useradd";
-pub const REMOVE_USER_CMD: &'static str = "/usr/sbin/userdel";
-pub const UPDATE_USER_CMD: &'static str = "/usr/sbin/usermod";
+pub const UPDATE_PASSWORDS_CMD: &str = "/usr/sbin/chpasswd";
+pub const UPDATE_AGING_CMD: &str = "/usr/bin/chage";
 
-pub const UPDATE_PASSWORDS_CMD: &'static str = "/usr/sbin/chpasswd";
\ No newline at end of file
+pub const ARCHIVE_CMD: &str = "/bin/tar";
+pub const CHECK_SUDOERS_CMD: &str = "/usr/sbin/visudo";
@@ -0,0 +1,161 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/**
+Get the current date.
+
+# Returns
+The number of days since 1970-01-01.
+ */
+pub fn today() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| (d.as_secs() / 86400) as i64)
+}
+
+/**
+Get the current date and time, used to timestamp the messages of long-running commands.
+
+# Returns
+The UTC date and time (`YYYY-MM-DD HH:MM:SS`).
+ */
+pub fn now() -> String {
+    let seconds = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs() as i64);
+    let time = seconds % 86400;
+
+    format!(
+        "{} {:02}:{:02}:{:02}",
+        format_date(seconds / 86400),
+        time / 3600,
+        time % 3600 / 60,
+        time % 60
+    )
+}
+
+/**
+Parse an ISO 8601 date (`YYYY-MM-DD`).
+
+# Parameters
+* `date`: The date to parse.
+
+# Returns
+The number of days since 1970-01-01 (the unit of the shadow file), `None` when the date is invalid.
+ */
+pub fn parse_date(date: &str) -> Option<i64> {
+    let bytes = date.as_bytes();
+    let well_formed = bytes.len() == 10
+        && bytes[4] == b'-'
+        && bytes[7] == b'-'
+        && bytes
+            .iter()
+            .enumerate()
+            .all(|(i, b)| i == 4 || i == 7 || b.is_ascii_digit());
+    if !well_formed {
+        return None;
+    }
+
+    let year: i64 = date[0..4].parse().ok()?;
+    let month: i64 = date[5..7].parse().ok()?;
+    let day: i64 = date[8..10].parse().ok()?;
+    if !(1..=12).contains(&month) || day < 1 || day > days_in_month(year, month) {
+        return None;
+    }
+
+    // Days from civil, the year starts in March so the leap day is the last one.
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * (month + if month > 2 { -3 } else { 9 }) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+
+    Some(era * 146097 + day_of_era - 719468)
+}
+
+/**
+Format a number of days since 1970-01-01 as an ISO 8601 date (`YYYY-MM-DD`).
+
+# Parameters
+* `days`: The number of days since 1970-01-01.
+ */
+pub fn format_date(days: i64) -> String {
+    // Civil from days, the year starts in March so the leap day is the last one.
+    let days = days + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days - era * 146097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+/**
+Get the number of days of a month.
+
+# Parameters
+* `year`: The year, for February.
+* `month`: The month, from 1 to 12.
+ */
+fn days_in_month(year: i64, month: i64) -> i64 {
+    match month {
+        2 if year % 4 == 0 && (year % 100 != 0 || year % 400 == 0) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dates_are_converted_to_days() {
+        for (date, days) in [
+            ("1969-12-31", -1),
+            ("1970-01-01", 0),
+            ("1970-01-02", 1),
+            ("2000-02-29", 11016),
+            ("2000-03-01", 11017),
+            ("2024-12-31", 20088),
+            ("1900-03-01", -25508),
+        ] {
+            assert_eq!(parse_date(date), Some(days), "{}", date);
+            assert_eq!(format_date(days), date);
+        }
+    }
+
+    #[test]
+    fn invalid_dates_are_rejected() {
+        for date in [
+            "",
+            "2024-1-01",
+            "2024/01/01",
+            "2024-00-10",
+            "2024-13-01",
+            "2024-04-31",
+            "2023-02-29",
+            "1900-02-29",
+            "+024-01-01",
+            "2024-01-01T00:00",
+        ] {
+            assert_eq!(parse_date(date), None, "{}", date);
+        }
+    }
+
+    #[test]
+    fn every_day_round_trips() {
+        for days in -800..30000 {
+            assert_eq!(parse_date(&format_date(days)), Some(days));
+        }
+    }
+}
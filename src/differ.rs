@@ -1,13 +1,107 @@
+use std::fmt;
+
+use serde::Serialize;
+
 use crate::{
-    sync_structs::{Group, LocalRoot, User},
-    system_parser::{SystemGroup, SystemUser},
+    consts,
+    filter::IdFilter,
+    s,
+    state::ManagedState,
+    sudo,
+    sync_structs::{AccountState, Group, LocalRoot, User},
+    system_parser::{AgingField, SystemGroup, SystemUser},
 };
 
 #[derive(Debug)]
 pub struct Differ {
     pub add: (Vec<User>, Vec<Group>),
-    pub update: (Vec<User>, Vec<Group>),
+    pub update: (Vec<UserUpdate>, Vec<GroupUpdate>),
     pub remove: (Vec<SystemUser>, Vec<SystemGroup>),
+    /// The change of the sudoers drop-in, `None` when it is up to date.
+    pub sudoers: Option<SudoersChange>,
+}
+
+/// A change of the sudoers drop-in.
+#[derive(Debug, Clone, PartialEq)]
+pub enum SudoersChange {
+    /// Write the drop-in with the given content.
+    Write(String),
+    /// Remove the drop-in, there's no rule left.
+    Remove,
+}
+
+/// A user present on both sides with at least one field to change.
+#[derive(Debug, Clone)]
+pub struct UserUpdate {
+    pub user: User,
+    pub changes: Vec<UserChange>,
+}
+
+/// A single field change of a user.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "change", rename_all = "snake_case")]
+pub enum UserChange {
+    PrimaryGroup {
+        from: String,
+        to: String,
+    },
+    SupplementaryGroups {
+        add: Vec<String>,
+        remove: Vec<String>,
+    },
+    Uid {
+        from: u32,
+        to: u32,
+    },
+    Gecos {
+        from: String,
+        to: String,
+    },
+    Home {
+        from: String,
+        to: String,
+    },
+    Shell {
+        from: String,
+        to: String,
+    },
+    Aging {
+        field: AgingField,
+        from: Option<i64>,
+        to: Option<i64>,
+    },
+    State {
+        from: AccountState,
+        to: AccountState,
+    },
+    /// The lines of the `authorized_keys` file to add and remove.
+    SshKeys {
+        add: Vec<String>,
+        remove: Vec<String>,
+    },
+    Password,
+}
+
+/// A group present on both sides with at least one field to change.
+#[derive(Debug, Clone)]
+pub struct GroupUpdate {
+    pub group: Group,
+    pub changes: Vec<GroupChange>,
+}
+
+/// A single field change of a group.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "change", rename_all = "snake_case")]
+pub enum GroupChange {
+    Gid {
+        from: u32,
+        to: u32,
+    },
+    /// The members which aren't synchronized users, the others are managed by `UserChange`.
+    Members {
+        add: Vec<String>,
+        remove: Vec<String>,
+    },
 }
 
 impl Differ {
@@ -17,18 +111,112 @@ impl Differ {
     # Parameters
     * `sync`: A reference to the `LocalRoot` of the sync file.
     * `system`: A reference to the tuple with the list of system users (0) and system groups (1).
+    * `managed`: The users and groups managed by the application,
+      `None` to consider every system user and group as managed.
+    * `sudoers`: The content of the sudoers drop-in, `None` when it doesn't exist.
+    * `filter`: The filter selecting the system users and groups which can be removed.
 
     # Returns
     An instance of a `Differ` with the result of a diff.
      */
-    pub fn new(sync: &LocalRoot, system: &(Vec<SystemUser>, Vec<SystemGroup>)) -> Self {
+    pub fn new(
+        sync: &LocalRoot,
+        system: &(Vec<SystemUser>, Vec<SystemGroup>),
+        managed: Option<&ManagedState>,
+        sudoers: Option<&str>,
+        filter: &IdFilter,
+    ) -> Self {
         Self {
             add: Self::detect_add(sync, system),
             update: Self::detect_update(sync, system),
-            remove: Self::detect_remove(sync, system),
+            remove: Self::detect_remove(sync, system, managed, filter),
+            sudoers: Self::detect_sudoers(sync, sudoers),
         }
     }
 
+    /**
+    Count the number of users and groups affected by the diff.
+
+    # Returns
+    The total number of entities to add, update or remove.
+     */
+    pub fn count(&self) -> usize {
+        self.add.0.len()
+            + self.add.1.len()
+            + self.update.0.len()
+            + self.update.1.len()
+            + self.remove.0.len()
+            + self.remove.1.len()
+    }
+
+    /**
+    Check if the system is already synchronized.
+
+    # Returns
+    `true` when there's nothing to add, update or remove, and the sudoers drop-in is up to date.
+     */
+    pub fn is_empty(&self) -> bool {
+        self.count() == 0 && self.sudoers.is_none()
+    }
+
+    /**
+    Summarize the diff, one line per user, group or drop-in.
+    The added and updated users and groups name the source they come from (`local` or `ldap`).
+
+    # Returns
+    The description of every difference between the system and the sync file.
+     */
+    pub fn summary(&self) -> Vec<String> {
+        let changes = |changes: Vec<String>| changes.join(", ");
+
+        let mut lines = Vec::<String>::new();
+        lines.extend(
+            self.add
+                .1
+                .iter()
+                .map(|g| format!("add group {} from {}", g.name, g.source)),
+        );
+        lines.extend(
+            self.add
+                .0
+                .iter()
+                .map(|u| format!("add user {} from {}", u.username, u.source)),
+        );
+        lines.extend(self.update.1.iter().map(|update| {
+            format!(
+                "update group {} from {} ({})",
+                update.group.name,
+                update.group.source,
+                changes(update.changes.iter().map(|c| c.to_string()).collect())
+            )
+        }));
+        lines.extend(self.update.0.iter().map(|update| {
+            format!(
+                "update user {} from {} ({})",
+                update.user.username,
+                update.user.source,
+                changes(update.changes.iter().map(|c| c.to_string()).collect())
+            )
+        }));
+        lines.extend(
+            self.remove
+                .0
+                .iter()
+                .map(|u| format!("remove user {}", u.username)),
+        );
+        lines.extend(
+            self.remove
+                .1
+                .iter()
+                .map(|g| format!("remove group {}", g.name)),
+        );
+        if let Some(change) = &self.sudoers {
+            lines.push(format!("sudoers drop-in ({})", change));
+        }
+
+        lines
+    }
+
     /**
     Find all the users and groups that needs to be added to the system.
 
@@ -56,7 +244,7 @@ impl Differ {
                     .find(|system_user| system_user.username == sync_user.username)
                     .is_none()
             })
-            .map(|user| user.clone())
+            .cloned()
             .collect();
 
         // Look for any user in the `sync_groups` list and not in the `system_groups` list.
@@ -68,7 +256,7 @@ impl Differ {
                     .find(|system_group| system_group.name == sync_group.name)
                     .is_none()
             })
-            .map(|group| group.clone())
+            .cloned()
             .collect();
 
         (add_users, add_groups)
@@ -76,55 +264,321 @@ impl Differ {
 
     /**
     Find all the users and groups that needs to be updated to the system.
+    Only the entities with at least one changed field are returned.
 
     # Parameters
     * `sync`: A reference to the `LocalRoot` of the sync file.
     * `system`: A reference to the tuple with the list of system users (0) and system groups (1).
 
     # Returns
-    A tuple with the list of users (0) and groups (1) to be updated to the system.
+    A tuple with the list of user (0) and group (1) changes to apply to the system.
      */
     fn detect_update(
         sync: &LocalRoot,
         system: &(Vec<SystemUser>, Vec<SystemGroup>),
-    ) -> (Vec<User>, Vec<Group>) {
+    ) -> (Vec<UserUpdate>, Vec<GroupUpdate>) {
         let sync_users = &sync.users;
         let sync_groups = &sync.groups;
         let (system_users, system_groups) = system;
 
-        // Look for any user in the `sync_users` list and not in the `system_users` list.
-        let update_users: Vec<User> = sync_users
+        // Look for any user in both the `sync_users` and `system_users` lists with changes.
+        let update_users: Vec<UserUpdate> = sync_users
             .iter()
-            .filter(|sync_user| {
-                system_users
+            .filter_map(|sync_user| {
+                let system_user = system_users
                     .iter()
-                    .find(|system_user| system_user.username == sync_user.username)
-                    .is_some()
+                    .find(|system_user| system_user.username == sync_user.username)?;
+                let changes = Self::user_changes(sync_user, system_user, system_groups, sync);
+
+                match changes.is_empty() {
+                    true => None,
+                    false => Some(UserUpdate {
+                        user: sync_user.clone(),
+                        changes,
+                    }),
+                }
             })
-            .map(|user| user.clone())
             .collect();
 
-        // Look for any user in the `sync_groups` list and not in the `system_groups` list.
-        let update_groups: Vec<Group> = sync_groups
+        // Look for any group in both the `sync_groups` and `system_groups` lists with changes.
+        let update_groups: Vec<GroupUpdate> = sync_groups
             .iter()
-            .filter(|sync_group| {
-                system_groups
+            .filter_map(|sync_group| {
+                let system_group = system_groups
                     .iter()
-                    .find(|system_group| system_group.name == sync_group.name)
-                    .is_some()
+                    .find(|system_group| system_group.name == sync_group.name)?;
+                let changes = Self::group_changes(sync_group, system_group, sync);
+
+                match changes.is_empty() {
+                    true => None,
+                    false => Some(GroupUpdate {
+                        group: sync_group.clone(),
+                        changes,
+                    }),
+                }
             })
-            .map(|group| group.clone())
             .collect();
 
         (update_users, update_groups)
     }
 
+    /**
+    Compute the changes needed to bring a system user in line with its sync definition.
+
+    # Parameters
+    * `sync_user`: The user as defined in the sync file.
+    * `system_user`: The matching user on the system.
+    * `system_groups`: The list of system groups.
+    * `sync`: A reference to the `LocalRoot` of the sync file.
+
+    # Returns
+    The list of changes, empty when the user is already synchronized.
+     */
+    fn user_changes(
+        sync_user: &User,
+        system_user: &SystemUser,
+        system_groups: &[SystemGroup],
+        sync: &LocalRoot,
+    ) -> Vec<UserChange> {
+        let mut changes = Vec::<UserChange>::new();
+        let state = system_user.state();
+        // The shell and expiry date of a disabled account are owned by the account state.
+        let state_owns_login =
+            sync_user.state == AccountState::Disabled || state == AccountState::Disabled;
+
+        // Primary group, compared by GID since the primary group may be outside the system list.
+        if let Some(sync_primary) = sync_user.groups.first() {
+            let sync_primary_gid = system_groups
+                .iter()
+                .find(|g| &g.name == sync_primary)
+                .map(|g| g.gid);
+            if sync_primary_gid != Some(system_user.gid) {
+                let from = system_groups
+                    .iter()
+                    .find(|g| g.gid == system_user.gid)
+                    .map(|g| g.name.clone())
+                    .unwrap_or_else(|| system_user.gid.to_string());
+                changes.push(UserChange::PrimaryGroup {
+                    from,
+                    to: sync_primary.clone(),
+                });
+            }
+        }
+
+        // Supplementary groups, compared as sets without the primary group
+        // which may or may not be listed in the group file.
+        let primary = sync_user.groups.first();
+        let current_supp: Vec<&String> = system_groups
+            .iter()
+            .filter(|g| g.users.contains(&sync_user.username) && Some(&g.name) != primary)
+            .map(|g| &g.name)
+            .collect();
+        let mut sync_supp = Vec::<&String>::new();
+        for group in sync_user.groups.iter().skip(1) {
+            if Some(group) != primary && !sync_supp.contains(&group) {
+                sync_supp.push(group);
+            }
+        }
+        let add: Vec<String> = sync_supp
+            .iter()
+            .filter(|g| !current_supp.contains(g))
+            .map(|g| (*g).clone())
+            .collect();
+        let remove: Vec<String> = current_supp
+            .iter()
+            .filter(|g| !sync_supp.contains(g))
+            .map(|g| (*g).clone())
+            .collect();
+        if !add.is_empty() || !remove.is_empty() {
+            changes.push(UserChange::SupplementaryGroups { add, remove });
+        }
+
+        // UID
+        if let Some(uid) = sync_user.uid {
+            if uid != system_user.uid {
+                changes.push(UserChange::Uid {
+                    from: system_user.uid,
+                    to: uid,
+                });
+            }
+        }
+
+        // GECOS, home directory and login shell, only managed when set in the sync file.
+        if let Some(gecos) = &sync_user.gecos {
+            if gecos != &system_user.gecos {
+                changes.push(UserChange::Gecos {
+                    from: system_user.gecos.clone(),
+                    to: gecos.clone(),
+                });
+            }
+        }
+        if let Some(home) = &sync_user.home {
+            if home != &system_user.home {
+                changes.push(UserChange::Home {
+                    from: system_user.home.clone(),
+                    to: home.clone(),
+                });
+            }
+        }
+        if let Some(shell) = sync_user.shell.as_ref().filter(|_| !state_owns_login) {
+            if shell != &system_user.shell {
+                changes.push(UserChange::Shell {
+                    from: system_user.shell.clone(),
+                    to: shell.clone(),
+                });
+            }
+        }
+
+        // Password aging, only the fields set in the sync file.
+        for (field, to) in sync_user.aging_fields() {
+            if field == AgingField::Expire && state_owns_login {
+                continue;
+            }
+
+            let from = system_user.aging.get(field);
+            if from != to {
+                changes.push(UserChange::Aging { field, from, to });
+            }
+        }
+
+        // Account state, a lock without a password behind is left to the password update.
+        let lock_only = system_user.hash.trim_start_matches('!').is_empty();
+        let unlock_only = sync_user.state == AccountState::Active && state == AccountState::Locked;
+        if sync_user.state != state && !(unlock_only && lock_only) {
+            changes.push(UserChange::State {
+                from: state,
+                to: sync_user.state,
+            });
+        } else if state == AccountState::Disabled && system_user.shell != consts::NOLOGIN_SHELL {
+            changes.push(UserChange::Shell {
+                from: system_user.shell.clone(),
+                to: s!(consts::NOLOGIN_SHELL),
+            });
+        }
+
+        // SSH keys, only managed when set in the sync file.
+        if let Some(sync_keys) = sync_user.ssh_key_lines() {
+            let current_keys = system_user
+                .authorized_keys
+                .current_keys(sync_user.ssh_keys_mode);
+            let add: Vec<String> = sync_keys
+                .iter()
+                .filter(|k| !current_keys.contains(k))
+                .cloned()
+                .collect();
+            let remove: Vec<String> = current_keys
+                .iter()
+                .filter(|k| !sync_keys.contains(k))
+                .cloned()
+                .collect();
+            if !add.is_empty() || !remove.is_empty() {
+                changes.push(UserChange::SshKeys { add, remove });
+            }
+        }
+
+        // Password, the hash is compared when the sync file holds it, otherwise
+        // the plain text password is verified against the hash.
+        // The password of a locked or disabled account is kept until it is active again,
+        // the lock is removed by the state change.
+        // The lock prefix is ignored on both sides, and a hash without any usable password
+        // (`!` or `*`, e.g. an LDAP account without password) matches a locked system entry.
+        let hash = system_user.hash.trim_start_matches('!');
+        let no_password = |hash: &str| {
+            hash == "*" || (!hash.is_empty() && hash.trim_start_matches('!').is_empty())
+        };
+        let same_password = match sync.is_encrypted(sync_user) {
+            true => {
+                sync_user.password.trim_start_matches('!') == hash
+                    || (no_password(&sync_user.password)
+                        && (system_user.is_locked() || system_user.hash == "*"))
+            }
+            false => pwhash::unix::verify(&sync_user.password, hash),
+        };
+        if sync_user.state == AccountState::Active && !same_password {
+            changes.push(UserChange::Password);
+        }
+
+        changes
+    }
+
+    /**
+    Compute the changes needed to bring a system group in line with its sync definition.
+
+    # Parameters
+    * `sync_group`: The group as defined in the sync file.
+    * `system_group`: The matching group on the system.
+    * `sync`: A reference to the `LocalRoot` of the sync file.
+
+    # Returns
+    The list of changes, empty when the group is already synchronized.
+     */
+    fn group_changes(
+        sync_group: &Group,
+        system_group: &SystemGroup,
+        sync: &LocalRoot,
+    ) -> Vec<GroupChange> {
+        let mut changes = Vec::<GroupChange>::new();
+
+        // GID
+        if let Some(gid) = sync_group.gid {
+            if gid != system_group.gid {
+                changes.push(GroupChange::Gid {
+                    from: system_group.gid,
+                    to: gid,
+                });
+            }
+        }
+
+        // Members, the synchronized users are left to their own `groups`.
+        if let Some(members) = &sync_group.members {
+            let add: Vec<String> = members
+                .iter()
+                .filter(|m| !system_group.users.contains(m))
+                .cloned()
+                .collect();
+            let remove: Vec<String> = system_group
+                .users
+                .iter()
+                .filter(|m| !members.contains(m) && !sync.has_user(m))
+                .cloned()
+                .collect();
+            if !add.is_empty() || !remove.is_empty() {
+                changes.push(GroupChange::Members { add, remove });
+            }
+        }
+
+        changes
+    }
+
+    /**
+    Compare the sudoers drop-in with the rules of the sync file.
+
+    # Parameters
+    * `sync`: A reference to the `LocalRoot` of the sync file.
+    * `current`: The content of the drop-in, `None` when it doesn't exist.
+
+    # Returns
+    The change of the drop-in, `None` when it is up to date.
+     */
+    fn detect_sudoers(sync: &LocalRoot, current: Option<&str>) -> Option<SudoersChange> {
+        match (sudo::render(sync), current) {
+            (Some(content), current) if current != Some(content.as_str()) => {
+                Some(SudoersChange::Write(content))
+            }
+            (None, Some(_)) => Some(SudoersChange::Remove),
+            _ => None,
+        }
+    }
+
     /**
     Find all the users and groups that needs to be removed from the system.
+    Only the managed users and groups accepted by the filter are considered.
 
     # Parameters
     * `sync`: A reference to the `LocalRoot` of the sync file.
     * `system`: A reference to the tuple with the list of system users (0) and system groups (1).
+    * `managed`: The users and groups managed by the application, `None` when all of them are.
+    * `filter`: The filter selecting the system users and groups handled by the application.
 
     # Returns
     A tuple with the list of users (0) and groups (1) to be removed from the system.
@@ -132,35 +586,181 @@ impl Differ {
     fn detect_remove(
         sync: &LocalRoot,
         system: &(Vec<SystemUser>, Vec<SystemGroup>),
+        managed: Option<&ManagedState>,
+        filter: &IdFilter,
     ) -> (Vec<SystemUser>, Vec<SystemGroup>) {
         let sync_users = &sync.users;
         let sync_groups = &sync.groups;
         let (system_users, system_groups) = system;
 
-        // Look for any user in the `system_users` list and not in the `sync_users` list.
+        // Look for any managed user in the `system_users` list and not in the `sync_users` list.
         let remove_users: Vec<SystemUser> = system_users
             .iter()
             .filter(|system_user| {
-                sync_users
-                    .iter()
-                    .find(|sync_user| system_user.username == sync_user.username)
-                    .is_none()
+                filter.accept_user(&system_user.username, system_user.uid)
+                    && managed.is_none_or(|m| m.users.contains(&system_user.username))
+                    && !sync_users
+                        .iter()
+                        .any(|sync_user| system_user.username == sync_user.username)
             })
-            .map(|user| user.clone())
+            .cloned()
             .collect();
 
-        // Look for any user in the `system_groups` list and not in the `sync_groups` list.
+        // Look for any managed group in the `system_groups` list and not in the `sync_groups` list.
         let remove_groups: Vec<SystemGroup> = system_groups
             .iter()
             .filter(|system_group| {
-                sync_groups
-                    .iter()
-                    .find(|sync_group| system_group.name == sync_group.name)
-                    .is_none()
+                filter.accept_group(&system_group.name, system_group.gid)
+                    && managed.is_none_or(|m| m.groups.contains(&system_group.name))
+                    && !sync_groups
+                        .iter()
+                        .any(|sync_group| system_group.name == sync_group.name)
             })
-            .map(|user| user.clone())
+            .cloned()
             .collect();
 
         (remove_users, remove_groups)
     }
 }
+
+impl fmt::Display for UserChange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::PrimaryGroup { from, to } => write!(f, "primary group {} -> {}", from, to),
+            Self::SupplementaryGroups { add, remove } => {
+                write!(
+                    f,
+                    "supplementary groups +[{}] -[{}]",
+                    add.join(","),
+                    remove.join(",")
+                )
+            }
+            Self::Uid { from, to } => write!(f, "UID {} -> {}", from, to),
+            Self::Gecos { from, to } => write!(f, "GECOS '{}' -> '{}'", from, to),
+            Self::Home { from, to } => write!(f, "home {} -> {}", from, to),
+            Self::Shell { from, to } => write!(f, "shell {} -> {}", from, to),
+            Self::Aging { field, from, to } => write!(
+                f,
+                "{} {} -> {}",
+                field,
+                field.format_value(*from),
+                field.format_value(*to)
+            ),
+            Self::State { from, to } => write!(f, "state {} -> {}", from, to),
+            Self::SshKeys { add, remove } => {
+                write!(f, "SSH keys +{} -{}", add.len(), remove.len())
+            }
+            Self::Password => write!(f, "password"),
+        }
+    }
+}
+
+impl fmt::Display for SudoersChange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Write(content) => write!(f, "write {} rule(s)", content.lines().count() - 1),
+            Self::Remove => write!(f, "remove"),
+        }
+    }
+}
+
+impl fmt::Display for GroupChange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Gid { from, to } => write!(f, "GID {} -> {}", from, to),
+            Self::Members { add, remove } => {
+                write!(f, "members +[{}] -[{}]", add.join(","), remove.join(","))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{date, ssh::AuthorizedKeys, system_parser::ShadowAging};
+
+    /**
+    Build a locked system user.
+
+    # Parameters
+    * `expire`: The expiry date of the account, in days since 1970-01-01.
+     */
+    fn locked_user(expire: Option<i64>) -> SystemUser {
+        SystemUser {
+            username: s!("eve"),
+            hash: s!("!$6$salt$hash"),
+            uid: 1000,
+            gid: 1000,
+            gecos: String::new(),
+            home: s!("/home/eve"),
+            shell: s!("/bin/sh"),
+            aging: ShadowAging {
+                expire,
+                ..ShadowAging::default()
+            },
+            authorized_keys: AuthorizedKeys::default(),
+        }
+    }
+
+    /**
+    Compute the changes of the user `eve` of a sync file.
+
+    # Parameters
+    * `json`: The local section of the sync file, in JSON.
+    * `system_user`: The user on the system.
+     */
+    fn changes(json: &str, system_user: &SystemUser) -> Vec<UserChange> {
+        let sync: LocalRoot = serde_json::from_str(json).unwrap();
+        let groups = vec![SystemGroup {
+            name: s!("staff"),
+            gid: 1000,
+            users: vec![],
+        }];
+        Differ::user_changes(&sync.users[0], system_user, &groups, &sync)
+    }
+
+    #[test]
+    fn locked_account_with_past_expiry_is_synchronized() {
+        let sync = r#"{
+            "encrypted": true,
+            "users": [{
+                "username": "eve",
+                "password": "$6$salt$hash",
+                "groups": ["staff"],
+                "aging": { "expire": "2000-01-01" },
+                "state": "locked"
+            }],
+            "groups": [{ "name": "staff" }]
+        }"#;
+        let system_user = locked_user(date::parse_date("2000-01-01"));
+
+        assert_eq!(system_user.state(), AccountState::Locked);
+        assert_eq!(changes(sync, &system_user), vec![]);
+    }
+
+    #[test]
+    fn disabled_account_is_locked_again() {
+        let sync = r#"{
+            "encrypted": true,
+            "users": [{
+                "username": "eve",
+                "password": "$6$salt$hash",
+                "groups": ["staff"],
+                "aging": { "expire": "2000-01-01" },
+                "state": "locked"
+            }],
+            "groups": [{ "name": "staff" }]
+        }"#;
+        let system_user = locked_user(Some(consts::DISABLED_EXPIRE));
+
+        assert_eq!(system_user.state(), AccountState::Disabled);
+        assert_eq!(
+            changes(sync, &system_user),
+            vec![UserChange::State {
+                from: AccountState::Disabled,
+                to: AccountState::Locked
+            }]
+        );
+    }
+}
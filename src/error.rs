@@ -0,0 +1,204 @@
+use std::{fmt, io, path::PathBuf};
+
+use serde::Serialize;
+
+use crate::backend::CommandRun;
+
+/**
+The errors of the application.
+Each error maps to a documented exit code.
+ */
+#[derive(Debug)]
+pub enum Error {
+    /// The sync file couldn't be read.
+    SyncRead(PathBuf, io::Error),
+    /// The system user or password file couldn't be read or parsed.
+    SystemUserRead(PathBuf, io::Error),
+    /// The system group file couldn't be read or parsed.
+    SystemGroupRead(PathBuf, io::Error),
+    /// The sync file couldn't be parsed.
+    SyncParse(String),
+    /// The sync file content is invalid.
+    InvalidSync(String),
+    /// The command line arguments are invalid.
+    Arguments(String),
+    /// The managed state file couldn't be read or written.
+    State(io::Error),
+    /// The login definitions couldn't be read.
+    LoginDefs(io::Error),
+    /// Some operations failed while applying the diff.
+    Apply(Summary),
+    /// The users and groups couldn't be fetched from the LDAP directory.
+    Ldap(String),
+    /// The snapshot of the user database couldn't be taken or restored.
+    Snapshot(io::Error),
+    /// The sudoers drop-in couldn't be read.
+    Sudoers(io::Error),
+    /// The sync file couldn't be watched.
+    Watch(io::Error),
+    /// The system differs from the sync file (`check` command), with the summary of the differences.
+    Drift(Vec<String>),
+}
+
+impl Error {
+    /**
+    Get the exit code of the application for the error.
+     */
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Self::SyncRead(..) => 1,
+            Self::SystemUserRead(..) => 2,
+            Self::SystemGroupRead(..) => 3,
+            Self::SyncParse(_) => 4,
+            Self::InvalidSync(_) => 5,
+            Self::Arguments(_) => 6,
+            Self::State(_) => 7,
+            Self::LoginDefs(_) => 8,
+            Self::Apply(_) => 9,
+            Self::Ldap(_) => 10,
+            Self::Snapshot(_) => 11,
+            Self::Sudoers(_) => 12,
+            Self::Watch(_) => 13,
+            Self::Drift(_) => 14,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::SyncRead(path, e) => {
+                write!(f, "Sync file read error ({}): {}", path.display(), e)
+            }
+            Self::SystemUserRead(path, e) => {
+                write!(f, "System user parse error ({}): {}", path.display(), e)
+            }
+            Self::SystemGroupRead(path, e) => {
+                write!(f, "System group parse error ({}): {}", path.display(), e)
+            }
+            Self::SyncParse(e) => write!(f, "Sync file parse error: {}", e),
+            Self::InvalidSync(e) => write!(f, "Invalid sync file: {}", e),
+            Self::Arguments(e) => write!(f, "{}", e),
+            Self::State(e) => write!(f, "Managed state error: {}", e),
+            Self::LoginDefs(e) => write!(f, "Login definitions read error: {}", e),
+            Self::Apply(summary) => write!(f, "{}", summary),
+            Self::Ldap(e) => write!(f, "LDAP error: {}", e),
+            Self::Snapshot(e) => write!(f, "Snapshot error: {}", e),
+            Self::Sudoers(e) => write!(f, "Sudoers drop-in read error: {}", e),
+            Self::Watch(e) => write!(f, "Sync file watch error: {}", e),
+            Self::Drift(differences) => write!(
+                f,
+                "DRIFT: {} difference(s) with the sync file.\n\t{}",
+                differences.len(),
+                differences.join("\n\t")
+            ),
+        }
+    }
+}
+
+/**
+An operation which failed to apply.
+ */
+#[derive(Debug)]
+pub struct Failure {
+    /// The description of the operation (e.g. `add user alice`).
+    pub operation: String,
+    pub error: io::Error,
+}
+
+/**
+The result of an operation, reported by the JSON output.
+ */
+#[derive(Debug, Clone, Serialize)]
+pub struct OperationResult {
+    /// The description of the operation (e.g. `add user alice`).
+    pub operation: String,
+    pub success: bool,
+    pub error: Option<String>,
+    /// The commands run to apply the operation.
+    pub commands: Vec<CommandRun>,
+}
+
+/**
+The result of all the operations applied to the system.
+ */
+#[derive(Debug, Default)]
+pub struct Summary {
+    pub succeeded: usize,
+    pub failures: Vec<Failure>,
+    /// Every operation, in the order they were applied.
+    pub operations: Vec<OperationResult>,
+    /// The changes were undone by restoring the snapshot taken before the sync.
+    pub rolled_back: bool,
+}
+
+impl Summary {
+    /**
+    Record the result of an operation.
+
+    # Parameters
+    * `operation`: The description of the operation.
+    * `rst`: The result of the operation.
+    * `commands`: The commands run to apply the operation.
+     */
+    pub fn record(&mut self, operation: String, rst: io::Result<()>, commands: Vec<CommandRun>) {
+        self.operations.push(OperationResult {
+            operation: operation.clone(),
+            success: rst.is_ok(),
+            error: rst.as_ref().err().map(|e| e.to_string()),
+            commands,
+        });
+        match rst {
+            Ok(_) => self.succeeded += 1,
+            Err(error) => self.failures.push(Failure { operation, error }),
+        }
+    }
+
+    /**
+    Check if an operation was applied.
+
+    # Parameters
+    * `operation`: The description of the operation (e.g. `add user alice`).
+
+    # Returns
+    `true` when the operation was recorded and succeeded.
+     */
+    pub fn applied(&self, operation: &str) -> bool {
+        self.operations
+            .iter()
+            .any(|result| result.success && result.operation == operation)
+    }
+
+    /**
+    Convert the summary into a result.
+
+    # Returns
+    An `Error::Apply` when at least one operation failed.
+     */
+    pub fn into_result(self) -> Result<Self, Error> {
+        match self.failures.is_empty() {
+            true => Ok(self),
+            false => Err(Error::Apply(self)),
+        }
+    }
+}
+
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} operation(s) succeeded, {} failed.",
+            self.succeeded,
+            self.failures.len()
+        )?;
+        for failure in &self.failures {
+            let error = failure.error.to_string().replace('\n', "\n\t\t");
+            write!(f, "\n\t{}: {}", failure.operation, error)?;
+        }
+        if self.rolled_back {
+            write!(f, "\nThe changes were rolled back.")?;
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,364 @@
+use std::{fs, io, path::Path};
+
+use crate::{
+    backend::operation_error, consts, error::Error, s, sync_structs::Filter, system_parser,
+};
+
+/**
+A pattern matching a user or a group.
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub enum Pattern {
+    /// An inclusive range of ids (`65534` or `60000-65535`).
+    Id(u32, u32),
+    /// A name where `*` matches any characters and `?` a single character.
+    Name(String),
+}
+
+impl Pattern {
+    /**
+    Parse a pattern.
+    Anything which is not an id or a range of ids is a name pattern.
+
+    # Parameters
+    * `pattern`: The pattern to parse.
+     */
+    pub fn parse(pattern: &str) -> Self {
+        let ids: Vec<Option<u32>> = pattern.splitn(2, '-').map(|id| id.parse().ok()).collect();
+        match ids[..] {
+            [Some(id)] => Self::Id(id, id),
+            [Some(from), Some(to)] => Self::Id(from, to),
+            _ => Self::Name(s!(pattern)),
+        }
+    }
+
+    /**
+    Check if an entry matches the pattern.
+
+    # Parameters
+    * `name`: The name of the user or group.
+    * `id`: The id of the user or group.
+     */
+    pub fn matches(&self, name: &str, id: u32) -> bool {
+        match self {
+            Self::Id(from, to) => (*from..=*to).contains(&id),
+            Self::Name(pattern) => glob_match(pattern.as_bytes(), name.as_bytes()),
+        }
+    }
+}
+
+/**
+The filter selecting the system users and groups handled by the application.
+ */
+#[derive(Debug, Clone)]
+pub struct IdFilter {
+    pub min_uid: u32,
+    pub max_uid: u32,
+    pub min_gid: u32,
+    pub max_gid: u32,
+    pub ignore_users: Vec<Pattern>,
+    pub ignore_groups: Vec<Pattern>,
+}
+
+impl Default for IdFilter {
+    fn default() -> Self {
+        Self {
+            min_uid: consts::MIN_UID,
+            max_uid: u32::MAX,
+            min_gid: consts::MIN_GID,
+            max_gid: u32::MAX,
+            ignore_users: vec![Pattern::parse("nobody")],
+            ignore_groups: vec![Pattern::parse("nogroup")],
+        }
+    }
+}
+
+impl IdFilter {
+    /**
+    Build the filter from the sync file configuration.
+    The bounds missing from the configuration are read from `/etc/login.defs` (`UID_MIN`, `UID_MAX`, `GID_MIN` and `GID_MAX`),
+    then defaults to `consts::MIN_UID` and `consts::MIN_GID` without upper bound.
+
+    # Parameters
+    * `config`: The filter section of the sync file, if any.
+    * `root`: The root of the filesystem containing `/etc/login.defs`.
+
+    # Returns
+    The filter, or an error when `/etc/login.defs` exists but can't be read,
+    or when a range of ids is empty or includes `0` (`root`).
+     */
+    pub fn new(config: Option<&Filter>, root: &Path) -> Result<Self, Error> {
+        let defs = LoginDefs::read(root).map_err(Error::LoginDefs)?;
+        let mut filter = Self::default();
+
+        let config = match config {
+            Some(config) => config.clone(),
+            None => Filter::default(),
+        };
+        let pick = |configured: Option<u32>, key: &str, default: u32| {
+            configured.or_else(|| defs.get(key)).unwrap_or(default)
+        };
+        filter.min_uid = pick(config.min_uid, "UID_MIN", filter.min_uid);
+        filter.max_uid = pick(config.max_uid, "UID_MAX", filter.max_uid);
+        filter.min_gid = pick(config.min_gid, "GID_MIN", filter.min_gid);
+        filter.max_gid = pick(config.max_gid, "GID_MAX", filter.max_gid);
+
+        let patterns = |list: &[String]| list.iter().map(|p| Pattern::parse(p)).collect::<Vec<_>>();
+        filter.ignore_users.extend(patterns(&config.ignore_users));
+        filter.ignore_groups.extend(patterns(&config.ignore_groups));
+
+        for (field, min, max) in [
+            ("UID", filter.min_uid, filter.max_uid),
+            ("GID", filter.min_gid, filter.max_gid),
+        ] {
+            if min == 0 || min > max {
+                return Err(Error::InvalidSync(format!(
+                    "The {} range {}-{} of the filter is invalid, it must start at 1 or above and end after its start.",
+                    field, min, max
+                )));
+            }
+        }
+
+        Ok(filter)
+    }
+
+    /**
+    Check if a system user is handled by the application.
+
+    # Parameters
+    * `username`: The name of the user.
+    * `uid`: The id of the user.
+     */
+    pub fn accept_user(&self, username: &str, uid: u32) -> bool {
+        (self.min_uid..=self.max_uid).contains(&uid)
+            && !self.ignore_users.iter().any(|p| p.matches(username, uid))
+    }
+
+    /**
+    Check if a system group is handled by the application.
+
+    # Parameters
+    * `name`: The name of the group.
+    * `gid`: The id of the group.
+     */
+    pub fn accept_group(&self, name: &str, gid: u32) -> bool {
+        (self.min_gid..=self.max_gid).contains(&gid)
+            && !self.ignore_groups.iter().any(|p| p.matches(name, gid))
+    }
+
+    /**
+    Get the next free UID, after the highest UID handled by the application.
+
+    # Parameters
+    * `users`: The name and UID of every system user.
+     */
+    pub fn next_uid(&self, users: &[(String, u32)]) -> io::Result<u32> {
+        next_id(
+            users,
+            consts::UID_FIELD,
+            self.min_uid,
+            self.max_uid,
+            |name, id| self.accept_user(name, id),
+        )
+    }
+
+    /**
+    Get the next free GID, after the highest GID handled by the application.
+
+    # Parameters
+    * `groups`: The name and GID of every system group.
+     */
+    pub fn next_gid(&self, groups: &[(String, u32)]) -> io::Result<u32> {
+        next_id(
+            groups,
+            consts::GID_FIELD,
+            self.min_gid,
+            self.max_gid,
+            |name, id| self.accept_group(name, id),
+        )
+    }
+}
+
+/**
+Get the next free id after the highest id handled by the application.
+
+# Parameters
+* `ids`: The name and id of every entry.
+* `field`: The name of the id, used in the error message.
+* `min`: The lowest id which can be allocated.
+* `max`: The highest id which can be allocated.
+* `accept`: Check if an entry (name, id) is handled by the application.
+ */
+fn next_id(
+    ids: &[(String, u32)],
+    field: &str,
+    min: u32,
+    max: u32,
+    accept: impl Fn(&str, u32) -> bool,
+) -> io::Result<u32> {
+    let highest = ids
+        .iter()
+        .filter(|(name, id)| accept(name, *id))
+        .map(|(_, id)| *id)
+        .max();
+    // `None` once the ids are exhausted, the arithmetic can't overflow.
+    let mut id = match highest {
+        Some(id) if id >= min => id.checked_add(1),
+        _ => Some(min),
+    };
+    // Skip the ids used by the entries which aren't handled by the application.
+    while let Some(used) = id.filter(|id| ids.iter().any(|(_, used)| used == id)) {
+        id = used.checked_add(1);
+    }
+
+    match id.filter(|id| *id <= max) {
+        Some(id) => Ok(id),
+        None => Err(operation_error(format!(
+            "no free {} in {}-{}",
+            field, min, max
+        ))),
+    }
+}
+
+/**
+The numeric settings of `/etc/login.defs`.
+ */
+pub struct LoginDefs(Vec<(String, u32)>);
+
+impl LoginDefs {
+    /**
+    Read the numeric settings of `/etc/login.defs`.
+
+    # Parameters
+    * `root`: The root of the filesystem containing the file.
+
+    # Returns
+    The settings, empty when the file doesn't exist.
+     */
+    pub fn read(root: &Path) -> io::Result<Self> {
+        let path = system_parser::system_path(root, consts::LOGIN_DEFS_FILE);
+        let content = match fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Self(vec![])),
+            Err(e) => return Err(e),
+        };
+
+        let settings = content
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.starts_with('#'))
+            .filter_map(|line| {
+                let mut split = line.split_whitespace();
+                let key = split.next()?;
+                let value = split.next()?.parse().ok()?;
+                Some((s!(key), value))
+            })
+            .collect();
+
+        Ok(Self(settings))
+    }
+
+    /**
+    Get a setting value.
+
+    # Parameters
+    * `key`: The name of the setting.
+     */
+    pub fn get(&self, key: &str) -> Option<u32> {
+        self.0.iter().find(|(k, _)| k == key).map(|(_, v)| *v)
+    }
+}
+
+/**
+Match a name against a glob pattern supporting `*` and `?`.
+
+# Parameters
+* `pattern`: The pattern.
+* `name`: The name to match.
+ */
+fn glob_match(pattern: &[u8], name: &[u8]) -> bool {
+    match (pattern.first(), name.first()) {
+        (None, None) => true,
+        (Some(b'*'), _) => {
+            glob_match(&pattern[1..], name) || (!name.is_empty() && glob_match(pattern, &name[1..]))
+        }
+        (Some(b'?'), Some(_)) => glob_match(&pattern[1..], &name[1..]),
+        (Some(p), Some(n)) if p == n => glob_match(&pattern[1..], &name[1..]),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /**
+    Build the list of entries from their ids, `65534` is `nobody`.
+
+    # Parameters
+    * `ids`: The ids of the entries.
+     */
+    fn entries(ids: &[u32]) -> Vec<(String, u32)> {
+        ids.iter()
+            .map(|id| match id {
+                65534 => (s!("nobody"), *id),
+                _ => (format!("user{}", id), *id),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn next_id_skips_the_used_ids() {
+        let filter = IdFilter::default();
+        assert_eq!(filter.next_uid(&entries(&[0, 65534])).unwrap(), 1000);
+        assert_eq!(
+            filter.next_uid(&entries(&[1000, 1001, 65534])).unwrap(),
+            1002
+        );
+
+        let filter = IdFilter {
+            ignore_users: vec![Pattern::parse("1001")],
+            ..IdFilter::default()
+        };
+        assert_eq!(filter.next_uid(&entries(&[1000, 1001])).unwrap(), 1002);
+    }
+
+    #[test]
+    fn next_id_fails_when_the_range_is_exhausted() {
+        let filter = IdFilter::default();
+        assert!(filter.next_uid(&entries(&[u32::MAX])).is_err());
+        assert!(filter
+            .next_uid(&entries(&[u32::MAX - 1, u32::MAX]))
+            .is_err());
+
+        let filter = IdFilter {
+            min_uid: 1000,
+            max_uid: 1001,
+            ..IdFilter::default()
+        };
+        assert_eq!(filter.next_uid(&entries(&[1000])).unwrap(), 1001);
+        assert!(filter.next_uid(&entries(&[1000, 1001])).is_err());
+    }
+
+    #[test]
+    fn invalid_ranges_are_rejected() {
+        let root = Path::new("/nonexistent");
+        for config in [
+            Filter {
+                min_uid: Some(0),
+                ..Filter::default()
+            },
+            Filter {
+                min_gid: Some(2000),
+                max_gid: Some(1000),
+                ..Filter::default()
+            },
+        ] {
+            assert!(matches!(
+                IdFilter::new(Some(&config), root),
+                Err(Error::InvalidSync(_))
+            ));
+        }
+        assert!(IdFilter::new(None, root).is_ok());
+    }
+}
@@ -0,0 +1,503 @@
+use std::fs;
+
+use ldap3::{LdapConn, LdapConnSettings, Scope, SearchEntry};
+
+use crate::{
+    error::Error,
+    s,
+    sync_structs::{
+        AccountState, Aging, AuthorizedKeysMode, Group, HomePolicy, Ldap, LdapAttributes,
+        LdapSearch, LocalRoot, Source, User,
+    },
+};
+
+/// The default filter of the user search.
+const DEFAULT_USER_FILTER: &str = "(objectClass=posixAccount)";
+/// The default filter of the group search.
+const DEFAULT_GROUP_FILTER: &str = "(objectClass=posixGroup)";
+/// The scheme prefix of the crypt hashes in the password attribute.
+const CRYPT_SCHEME: &str = "{CRYPT}";
+/// The password of the users without a crypt hash, password login is disabled.
+const LOCKED_PASSWORD: &str = "!";
+
+/**
+Fetch the users and groups from an LDAP directory.
+
+# Parameters
+* `config`: The LDAP section of the sync file.
+* `warnings`: The list receiving the entries which were skipped.
+
+# Returns
+The users and groups in the same shape as the local sync information.
+ */
+pub fn fetch(config: &Ldap, warnings: &mut Vec<String>) -> Result<LocalRoot, Error> {
+    let settings = LdapConnSettings::new().set_starttls(config.starttls);
+    let mut conn = LdapConn::with_settings(settings, &config.url).map_err(ldap_error)?;
+
+    if let Some(bind_dn) = &config.bind_dn {
+        let secret = bind_secret(config)?;
+        conn.simple_bind(bind_dn, &secret)
+            .and_then(|rst| rst.success())
+            .map_err(ldap_error)?;
+    }
+
+    let attrs = &config.attributes;
+    let user_entries = search(
+        &mut conn,
+        &config.users,
+        DEFAULT_USER_FILTER,
+        &[
+            &attrs.uid,
+            &attrs.uid_number,
+            &attrs.gid_number,
+            &attrs.user_password,
+            &attrs.gecos,
+            &attrs.home,
+            &attrs.shell,
+        ],
+    )?;
+    let group_entries = search(
+        &mut conn,
+        &config.groups,
+        DEFAULT_GROUP_FILTER,
+        &[
+            &attrs.group_name,
+            &attrs.gid_number,
+            &attrs.member_uid,
+            &attrs.member,
+        ],
+    )?;
+    let _ = conn.unbind();
+
+    Ok(build_root(&user_entries, &group_entries, attrs, warnings))
+}
+
+/**
+Get the password of the bind DN.
+
+# Parameters
+* `config`: The LDAP section of the sync file.
+
+# Returns
+The password from `bind_secret` or `bind_secret_file`, empty when none is set.
+ */
+fn bind_secret(config: &Ldap) -> Result<String, Error> {
+    if let Some(secret) = &config.bind_secret {
+        return Ok(secret.clone());
+    }
+
+    match &config.bind_secret_file {
+        Some(path) => fs::read_to_string(path)
+            .map(|secret| s!(secret.trim_end_matches(['\r', '\n'])))
+            .map_err(|e| {
+                Error::Ldap(format!(
+                    "bind secret read error ({}): {}",
+                    path.display(),
+                    e
+                ))
+            }),
+        None => Ok(String::new()),
+    }
+}
+
+/**
+Search the entries in every base DN.
+
+# Parameters
+* `conn`: The LDAP connection.
+* `search`: The base DNs and filter of the search.
+* `default_filter`: The filter used when none is configured.
+* `attrs`: The attributes to fetch.
+
+# Returns
+Every entry found.
+ */
+fn search(
+    conn: &mut LdapConn,
+    search: &LdapSearch,
+    default_filter: &str,
+    attrs: &[&String],
+) -> Result<Vec<SearchEntry>, Error> {
+    let filter = search.filter.as_deref().unwrap_or(default_filter);
+
+    let mut entries = Vec::<SearchEntry>::new();
+    for base_dn in &search.base_dns {
+        let (rs, _) = conn
+            .search(base_dn, Scope::Subtree, filter, attrs.to_vec())
+            .and_then(|rst| rst.success())
+            .map_err(ldap_error)?;
+        entries.extend(rs.into_iter().map(SearchEntry::construct));
+    }
+
+    Ok(entries)
+}
+
+/**
+Build the users and groups from the LDAP entries.
+The groups of a user are its primary group (`gidNumber`) followed by every group
+listing it in `memberUid` (by username) or `member` (by DN).
+
+# Parameters
+* `user_entries`: The user entries.
+* `group_entries`: The group entries.
+* `attrs`: The attribute mapping.
+* `warnings`: The list receiving the entries which were skipped.
+
+# Returns
+The users and groups, the passwords are crypt hashes (or disabled when the entry has none).
+ */
+fn build_root(
+    user_entries: &[SearchEntry],
+    group_entries: &[SearchEntry],
+    attrs: &LdapAttributes,
+    warnings: &mut Vec<String>,
+) -> LocalRoot {
+    let mut groups = Vec::<(Group, &SearchEntry)>::new();
+    for entry in group_entries {
+        let name = match first(entry, &attrs.group_name) {
+            Some(name) => name,
+            None => {
+                warnings.push(format!("LDAP group `{}` has no name, skipped.", entry.dn));
+                continue;
+            }
+        };
+        let gid = first(entry, &attrs.gid_number).and_then(|gid| gid.parse().ok());
+        groups.push((
+            Group {
+                name,
+                gid,
+                members: None,
+                sudo: vec![],
+                source: Source::Ldap,
+            },
+            entry,
+        ));
+    }
+
+    let mut users = Vec::<User>::new();
+    for entry in user_entries {
+        let username = match first(entry, &attrs.uid) {
+            Some(username) => username,
+            None => {
+                warnings.push(format!(
+                    "LDAP user `{}` has no username, skipped.",
+                    entry.dn
+                ));
+                continue;
+            }
+        };
+        let uid = first(entry, &attrs.uid_number).and_then(|uid| uid.parse().ok());
+        let gid: Option<u32> = first(entry, &attrs.gid_number).and_then(|gid| gid.parse().ok());
+
+        // Primary group.
+        let primary = match groups.iter().find(|(g, _)| g.gid.is_some() && g.gid == gid) {
+            Some((group, _)) => group.name.clone(),
+            None => {
+                warnings.push(format!(
+                    "LDAP user `{}` primary group ({:?}) not found, skipped.",
+                    username, gid
+                ));
+                continue;
+            }
+        };
+
+        // Supplementary groups.
+        let mut user_groups = vec![primary];
+        for (group, group_entry) in &groups {
+            let member_uid = values(group_entry, &attrs.member_uid)
+                .iter()
+                .any(|m| m == &username);
+            let member = values(group_entry, &attrs.member)
+                .iter()
+                .any(|m| m.eq_ignore_ascii_case(&entry.dn));
+            if (member_uid || member) && !user_groups.contains(&group.name) {
+                user_groups.push(group.name.clone());
+            }
+        }
+
+        users.push(User {
+            username,
+            uid,
+            password: password(entry, &attrs.user_password),
+            encrypted: Some(true),
+            groups: user_groups,
+            gecos: first(entry, &attrs.gecos),
+            home: first(entry, &attrs.home),
+            shell: first(entry, &attrs.shell),
+            aging: Aging::default(),
+            state: AccountState::default(),
+            home_policy: HomePolicy::default(),
+            ssh_keys: None,
+            ssh_keys_mode: AuthorizedKeysMode::default(),
+            sudo: vec![],
+            source: Source::Ldap,
+        });
+    }
+
+    LocalRoot {
+        encrypted: true,
+        users,
+        groups: groups.into_iter().map(|(group, _)| group).collect(),
+        home_policy: HomePolicy::default(),
+    }
+}
+
+/**
+Get the crypt hash of an entry password.
+
+# Parameters
+* `entry`: The user entry.
+* `attr`: The name of the password attribute.
+
+# Returns
+The crypt hash, or a disabled password when the password isn't a `{CRYPT}` hash.
+ */
+fn password(entry: &SearchEntry, attr: &str) -> String {
+    let value = first(entry, attr).or_else(|| {
+        entry
+            .bin_attrs
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(attr))
+            .and_then(|(_, v)| v.first())
+            .map(|v| String::from_utf8_lossy(v).into_owned())
+    });
+
+    let scheme_len = CRYPT_SCHEME.len();
+    match value {
+        Some(value)
+            if value.len() > scheme_len
+                && value
+                    .get(..scheme_len)
+                    .is_some_and(|scheme| scheme.eq_ignore_ascii_case(CRYPT_SCHEME)) =>
+        {
+            s!(&value[scheme_len..])
+        }
+        _ => s!(LOCKED_PASSWORD),
+    }
+}
+
+/**
+Get every value of an attribute, the attribute name is case insensitive.
+
+# Parameters
+* `entry`: The entry.
+* `attr`: The name of the attribute.
+ */
+fn values<'a>(entry: &'a SearchEntry, attr: &str) -> &'a [String] {
+    entry
+        .attrs
+        .iter()
+        .find(|(k, _)| k.eq_ignore_ascii_case(attr))
+        .map_or(&[], |(_, v)| v.as_slice())
+}
+
+/**
+Get the first value of an attribute, the attribute name is case insensitive.
+
+# Parameters
+* `entry`: The entry.
+* `attr`: The name of the attribute.
+ */
+fn first(entry: &SearchEntry, attr: &str) -> Option<String> {
+    values(entry, attr).first().cloned()
+}
+
+/**
+Convert an LDAP error into an application error.
+
+# Parameters
+* `e`: The LDAP error.
+ */
+fn ldap_error(e: ldap3::LdapError) -> Error {
+    Error::Ldap(e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    /**
+    Build an LDAP entry.
+
+    # Parameters
+    * `dn`: The DN of the entry.
+    * `attrs`: The attributes, with their values.
+     */
+    fn entry(dn: &str, attrs: &[(&str, &[&str])]) -> SearchEntry {
+        SearchEntry {
+            dn: s!(dn),
+            attrs: attrs
+                .iter()
+                .map(|(name, values)| (s!(*name), values.iter().map(|v| s!(*v)).collect()))
+                .collect(),
+            bin_attrs: HashMap::new(),
+        }
+    }
+
+    #[test]
+    fn entries_are_mapped_to_users_and_groups() {
+        let groups = [
+            entry(
+                "cn=staff,ou=groups,dc=example,dc=org",
+                &[
+                    ("cn", &["staff"]),
+                    ("gidNumber", &["1000"]),
+                    ("memberUid", &["bob"]),
+                ],
+            ),
+            entry(
+                "cn=devs,ou=groups,dc=example,dc=org",
+                &[
+                    ("cn", &["devs"]),
+                    ("gidNumber", &["1001"]),
+                    // The DNs are compared case insensitively.
+                    ("member", &["UID=alice,OU=people,DC=example,DC=org"]),
+                ],
+            ),
+        ];
+        let mut bob = entry(
+            "uid=bob,ou=people,dc=example,dc=org",
+            &[
+                ("uid", &["bob"]),
+                ("uidNumber", &["1001"]),
+                ("gidNumber", &["1001"]),
+            ],
+        );
+        bob.bin_attrs
+            .insert(s!("userPassword"), vec![b"{crypt}$6$salt$bob".to_vec()]);
+        let users = [
+            entry(
+                "uid=alice,ou=people,dc=example,dc=org",
+                &[
+                    ("uid", &["alice"]),
+                    ("uidNumber", &["1000"]),
+                    ("gidNumber", &["1000"]),
+                    ("userPassword", &["{SSHA}c2FsdGVk"]),
+                    ("gecos", &["Alice"]),
+                    ("HomeDirectory", &["/home/alice"]),
+                    ("loginShell", &["/bin/zsh"]),
+                ],
+            ),
+            bob,
+        ];
+        let mut warnings = vec![];
+
+        let root = build_root(&users, &groups, &LdapAttributes::default(), &mut warnings);
+        assert!(warnings.is_empty(), "{:?}", warnings);
+        assert!(root.encrypted);
+
+        let names: Vec<_> = root
+            .groups
+            .iter()
+            .map(|g| (g.name.as_str(), g.gid))
+            .collect();
+        assert_eq!(names, vec![("staff", Some(1000)), ("devs", Some(1001))]);
+        assert!(root.groups.iter().all(|g| g.source == Source::Ldap));
+
+        let alice = &root.users[0];
+        assert_eq!(
+            (alice.username.as_str(), alice.uid, alice.groups.clone()),
+            ("alice", Some(1000), vec![s!("staff"), s!("devs")])
+        );
+        // Only the crypt hashes can be synchronized, the password login is disabled otherwise.
+        assert_eq!(alice.password, LOCKED_PASSWORD);
+        assert_eq!(alice.gecos.as_deref(), Some("Alice"));
+        assert_eq!(alice.home.as_deref(), Some("/home/alice"));
+        assert_eq!(alice.shell.as_deref(), Some("/bin/zsh"));
+        assert_eq!(alice.source, Source::Ldap);
+
+        let bob = &root.users[1];
+        assert_eq!(bob.groups, vec![s!("devs"), s!("staff")]);
+        assert_eq!(bob.password, "$6$salt$bob");
+        assert_eq!(bob.encrypted, Some(true));
+        assert_eq!(bob.shell, None);
+    }
+
+    #[test]
+    fn malformed_entries_are_skipped() {
+        let groups = [
+            entry("cn=unnamed,dc=example,dc=org", &[("gidNumber", &["1000"])]),
+            entry(
+                "cn=staff,dc=example,dc=org",
+                &[("cn", &["staff"]), ("gidNumber", &["staff"])],
+            ),
+            entry(
+                "cn=devs,dc=example,dc=org",
+                &[("cn", &["devs"]), ("gidNumber", &["1001"])],
+            ),
+        ];
+        let users = [
+            entry(
+                "uid=anonymous,dc=example,dc=org",
+                &[("gidNumber", &["1001"])],
+            ),
+            // The GID of staff is invalid, it can't be a primary group.
+            entry(
+                "uid=alice,dc=example,dc=org",
+                &[("uid", &["alice"]), ("gidNumber", &["staff"])],
+            ),
+            entry(
+                "uid=bob,dc=example,dc=org",
+                &[("uid", &["bob"]), ("gidNumber", &["2000"])],
+            ),
+            entry(
+                "uid=carol,dc=example,dc=org",
+                &[
+                    ("uid", &["carol"]),
+                    ("uidNumber", &["-1"]),
+                    ("gidNumber", &["1001"]),
+                ],
+            ),
+        ];
+        let mut warnings = vec![];
+
+        let root = build_root(&users, &groups, &LdapAttributes::default(), &mut warnings);
+        assert_eq!(
+            warnings,
+            vec![
+                s!("LDAP group `cn=unnamed,dc=example,dc=org` has no name, skipped."),
+                s!("LDAP user `uid=anonymous,dc=example,dc=org` has no username, skipped."),
+                s!("LDAP user `alice` primary group (None) not found, skipped."),
+                s!("LDAP user `bob` primary group (Some(2000)) not found, skipped."),
+            ]
+        );
+        let names: Vec<_> = root
+            .groups
+            .iter()
+            .map(|g| (g.name.as_str(), g.gid))
+            .collect();
+        assert_eq!(names, vec![("staff", None), ("devs", Some(1001))]);
+        // An invalid UID is left to the allocation, like a missing one.
+        let names: Vec<_> = root
+            .users
+            .iter()
+            .map(|u| (u.username.as_str(), u.uid))
+            .collect();
+        assert_eq!(names, vec![("carol", None)]);
+    }
+
+    #[test]
+    fn attributes_can_be_renamed() {
+        let attrs = LdapAttributes {
+            uid: s!("sAMAccountName"),
+            group_name: s!("displayName"),
+            ..LdapAttributes::default()
+        };
+        let groups = [entry(
+            "cn=staff,dc=example,dc=org",
+            &[("displayName", &["staff"]), ("gidNumber", &["1000"])],
+        )];
+        let users = [entry(
+            "cn=Alice,dc=example,dc=org",
+            &[("samaccountname", &["alice"]), ("gidNumber", &["1000"])],
+        )];
+        let mut warnings = vec![];
+
+        let root = build_root(&users, &groups, &attrs, &mut warnings);
+        assert!(warnings.is_empty(), "{:?}", warnings);
+        assert_eq!(root.users[0].username, "alice");
+        assert_eq!(root.users[0].groups, vec![s!("staff")]);
+    }
+}
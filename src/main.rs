@@ -1,20 +1,56 @@
+mod backend;
+mod cli;
 mod consts;
+mod date;
 mod differ;
+mod error;
+mod filter;
+mod ldap;
+mod output;
 mod runner;
+mod snapshot;
+mod sources;
+mod ssh;
+mod state;
+mod sudo;
 mod sync_structs;
 mod system_parser;
+mod validator;
+mod watch;
 
-use std::{env, fs, path::PathBuf};
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+};
 
+use backend::{Backend, Files, Memory, ShadowUtils};
+use cli::{BackendKind, Command, Options, OutputFormat};
 use differ::Differ;
-use sync_structs::Root;
+use error::Error;
+use filter::IdFilter;
+use output::Report;
+use snapshot::Snapshot;
+use state::ManagedState;
+use sync_structs::{Format, LocalRoot, Root};
 use system_parser::{SystemGroup, SystemUser};
 
 /**
 Main function of the application.
 
 # Positional arguments:
-1. Path to the sync file (optional, can use environment variable).
+1. The command, `sync` (default), `validate`, `check`, `rollback` or `watch` (optional).
+2. Path to the sync file (optional, can use environment variable).
+
+# Options:
+* `--dry-run` (or `--plan`): Print every command that would be executed without running anything.
+* `--backend <shadow-utils|files|memory>`: The backend used to apply the changes.
+* `--format <json|yaml|toml>`: The format of the sync file (guessed from the extension by default).
+* `--root <path>`: The root of the filesystem to synchronize (defaults to `/`).
+* `--exhaustive`: Remove every user and group not in the sync file, not only the managed ones.
+* `--no-rollback`: Keep the applied changes when an operation fails.
+* `--adopt`: Also manage the existing users and groups of the sync file, e.g. created by hand.
+* `--output <text|json>`: Print a single JSON document with the plan and the result of every operation.
+* `--interval <seconds>`: With `watch`, also synchronize periodically even when the sync file didn't change.
 
 # Environment variables:
 * `USER_SYNC`: Path to the sync file. (Will default to `/etc/user-sync.json`).
@@ -26,92 +62,313 @@ Main function of the application.
 * `3`: Failed to read system group file.
 * `4`: Failed to parse sync file.
 * `5`: Invalid sync
+* `6`: Invalid command line arguments.
+* `7`: Failed to read or write the managed state file.
+* `8`: Failed to read `/etc/login.defs`.
+* `9`: Some operations failed to apply, the changes were rolled back (or kept with `--no-rollback`).
+* `10`: Failed to fetch the users and groups from LDAP.
+* `11`: Failed to take or restore the snapshot of the user database.
+* `12`: Failed to read the sudoers drop-in.
+* `13`: Failed to watch the sync file.
+* `14`: The system differs from the sync file (`check` command).
  */
 fn main() {
-    let path = get_sync_file_path();
-    let sync = get_sync_data(&path);
-    let system = get_system_data();
-
-    if let Some(local) = sync.local {
-        let differ = Differ::new(&local, &system);
-        //println!("{:?}", differ);
-        runner::apply_diff(&differ, &system, &local);
-    } else {
-        println!("Unable to find the local sync configuration.");
-        std::process::exit(5);
+    let (rst, output) = match Options::parse() {
+        Ok(options) => (run(&options), options.output),
+        Err(e) => (Err(Error::Arguments(e)), OutputFormat::Text),
+    };
+
+    if let Err(e) = rst {
+        match (&e, output) {
+            // The error is part of the JSON report.
+            (_, OutputFormat::Json) => {}
+            (Error::Arguments(_), _) => println!("{}\n\n{}", e, cli::usage()),
+            _ => println!("{}", e),
+        }
+        std::process::exit(e.exit_code());
+    }
+}
+
+/**
+Run the command given on the command line.
+
+# Parameters
+* `options`: The command line options of the application.
+
+# Returns
+An error when the command couldn't be done or partially failed.
+ */
+fn run(options: &Options) -> Result<(), Error> {
+    if options.help {
+        println!("{}", cli::usage());
+        return Ok(());
+    }
+
+    match options.command {
+        Command::Rollback => rollback(&options.root),
+        Command::Watch => watch::watch(&get_sync_file_path(options), options.interval, || {
+            sync(options, &mut Report::new(options.dry_run))
+        }),
+        Command::Sync | Command::Validate | Command::Check => {
+            let mut report = Report::new(options.dry_run);
+            let rst = sync(options, &mut report);
+            if options.output == OutputFormat::Json {
+                if let Err(e) = &rst {
+                    report.set_error(e);
+                }
+                println!("{}", report.to_json());
+            }
+            rst
+        }
     }
 }
 
+/**
+Run the synchronisation.
+
+# Parameters
+* `options`: The command line options of the application.
+* `report`: The report receiving the plan and the results, printed with the JSON output.
+
+# Returns
+An error when the synchronisation couldn't be done or partially failed.
+ */
+fn sync(options: &Options, report: &mut Report) -> Result<(), Error> {
+    // The JSON report is printed instead of the messages.
+    let text = options.output == OutputFormat::Text;
+
+    let path = get_sync_file_path(options);
+    let format = options.format.unwrap_or_else(|| Format::from_path(&path));
+    let sync = get_sync_data(&path, format)?;
+    let mut local = get_sync_source(&sync, &mut report.warnings)?;
+
+    // Validate the sync file before anything is applied.
+    let validation = validator::validate(&local);
+    report.warnings.extend(validation.warnings.iter().cloned());
+    if text {
+        for warning in &report.warnings {
+            println!("Warning: {}", warning);
+        }
+    }
+    if !validation.is_valid() {
+        return Err(Error::InvalidSync(format!(
+            "{} error(s) found.\n\t{}",
+            validation.errors.len(),
+            validation.errors.join("\n\t")
+        )));
+    }
+    if options.command == Command::Validate {
+        if text {
+            println!("The sync file is valid.");
+        }
+        return Ok(());
+    }
+    local.resolve_members();
+    let local = &local;
+
+    let filter = IdFilter::new(sync.filter.as_ref(), &options.root)?;
+    let system = get_system_data(&options.root)?;
+    let managed = ManagedState::load(&options.root).map_err(Error::State)?;
+    let sudoers = sudo::read(&options.root).map_err(Error::Sudoers)?;
+
+    let differ = Differ::new(
+        local,
+        &system,
+        match options.exhaustive {
+            true => None,
+            false => Some(&managed),
+        },
+        sudoers.as_deref(),
+        &filter,
+    );
+    report.set_plan(&differ);
+    if options.command == Command::Check {
+        return match differ.is_empty() {
+            true => {
+                if text {
+                    println!("OK: users and groups are synchronized.");
+                }
+                Ok(())
+            }
+            false => Err(Error::Drift(differ.summary())),
+        };
+    }
+    if text {
+        if differ.is_empty() {
+            println!("Users and groups are already synchronized.");
+        } else if differ.count() > 0 {
+            println!(
+                "{} user(s) and group(s) to synchronize: {} to add, {} to update, {} to remove.",
+                differ.count(),
+                differ.add.0.len() + differ.add.1.len(),
+                differ.update.0.len() + differ.update.1.len(),
+                differ.remove.0.len() + differ.remove.1.len(),
+            );
+        }
+        if let Some(change) = &differ.sudoers {
+            println!("The sudoers drop-in is out of date ({}).", change);
+        }
+    }
+
+    // Snapshot the user database so a failed sync can be rolled back,
+    // it only replaces the previous snapshot once the system was changed.
+    let writes = !options.dry_run && options.backend != BackendKind::Memory;
+    let snapshot = match writes && !differ.is_empty() {
+        true => Some(Snapshot::take(&options.root).map_err(Error::Snapshot)?),
+        false => None,
+    };
+
+    let quiet = !text;
+    let mut backend: Box<dyn Backend> = match options.backend {
+        BackendKind::ShadowUtils => {
+            Box::new(ShadowUtils::new(options.dry_run, quiet, &options.root))
+        }
+        BackendKind::Files => Box::new(Files::new(options.dry_run, quiet, &options.root, &filter)),
+        BackendKind::Memory => Box::new(Memory::new(system, &filter, quiet)),
+    };
+    let mut summary = runner::apply_diff(&differ, local, &managed, backend.as_mut());
+    // Release the user database.
+    drop(backend);
+
+    if let Some(snapshot) = snapshot {
+        if !summary.failures.is_empty() && !options.no_rollback {
+            if text {
+                println!("Rolling back the user database...");
+            }
+            snapshot.restore(&options.root).map_err(Error::Snapshot)?;
+            summary.rolled_back = true;
+        }
+        match summary.succeeded > 0 && !summary.rolled_back {
+            true => snapshot.keep(&options.root),
+            false => snapshot.discard(),
+        }
+        .map_err(Error::Snapshot)?;
+    }
+    report.set_summary(&summary);
+    if summary.rolled_back {
+        return Err(Error::Apply(summary));
+    }
+
+    // Record the managed users and groups once the system was modified.
+    if writes {
+        let system = get_system_data(&options.root)?;
+        managed
+            .after_sync(local, &differ, &summary, options.adopt, &system)
+            .save(&options.root)
+            .map_err(Error::State)?;
+    }
+
+    let summary = summary.into_result()?;
+    if text {
+        println!("{}", summary);
+    }
+
+    Ok(())
+}
+
+/**
+Restore the user database saved before the last sync.
+
+# Parameters
+* `root`: The root of the filesystem to restore.
+
+# Returns
+An error when there's no snapshot or it couldn't be restored.
+ */
+fn rollback(root: &Path) -> Result<(), Error> {
+    let snapshot = Snapshot::load(root).map_err(Error::Snapshot)?;
+    println!("Restoring the snapshot taken on {}...", snapshot.date());
+    for path in snapshot.restore(root).map_err(Error::Snapshot)? {
+        println!("\tRestored {}", path.display());
+    }
+
+    Ok(())
+}
+
 /**
 Get the file path from either the argument or the environment variable.
 
-It will tries to first get the path from the first argument of the application.\
+It will tries to first get the path from the first positional argument of the application.\
 If it's not able, it will try to read the `USER_SYNC` environment variable.\
 If it's still unable to get a path, it will just default to use `/etc/user-sync.json`.
 
+# Parameters
+* `options`: The command line options of the application.
+
 # Returns
 A `PathBuf` with the resolved path.
  */
-fn get_sync_file_path() -> PathBuf {
-    let file_path_opt = env::args().nth(1);
-    PathBuf::from(
-        match file_path_opt {
-            None => Some(env::var("USER_SYNC").unwrap_or(s!("/etc/user-sync.json"))),
-            _ => file_path_opt,
-        }
-        .unwrap(),
-    )
+fn get_sync_file_path(options: &Options) -> PathBuf {
+    match &options.sync_path {
+        Some(path) => path.clone(),
+        None => PathBuf::from(env::var("USER_SYNC").unwrap_or_else(|_| s!("/etc/user-sync.json"))),
+    }
 }
 
 /**
-Read and parse the synchonisation data from the given file.\
-Will exit the program on error.
+Read and parse the synchonisation data from the given file.
+
+# Parameters
+* `path`: The path of the sync file.
+* `format`: The format of the sync file.
 
 # Returns
-The parsed synchronisation file.
+The parsed synchronisation file, or an error when it can't be read or parsed.
  */
-fn get_sync_data(path: &PathBuf) -> Root {
-    match fs::read_to_string(path) {
-        Err(e) => {
-            println!("Sync file read error: {}", e);
-            std::process::exit(1);
-        }
-        Ok(content) => match serde_json::from_str(&content) {
-            Err(e) => {
-                println!("Sync file parse error: {}", e);
-                std::process::exit(4);
-            }
-            Ok(result) => result,
-        },
+fn get_sync_data(path: &Path, format: Format) -> Result<Root, Error> {
+    let content = fs::read_to_string(path).map_err(|e| Error::SyncRead(path.to_path_buf(), e))?;
+    Root::parse(&content, format).map_err(Error::SyncParse)
+}
+
+/**
+Get the users and groups to synchronize from the sync file sources.\
+The local configuration and the LDAP directory are merged following the sync file precedence,
+the conflicts between them are reported as warnings.
+
+# Parameters
+* `sync`: The parsed synchronisation file.
+* `warnings`: The list receiving the conflicts and the skipped LDAP entries.
+
+# Returns
+The users and groups to synchronize, or an error when no source is configured.
+ */
+fn get_sync_source(sync: &Root, warnings: &mut Vec<String>) -> Result<LocalRoot, Error> {
+    if sync.local.is_none() && sync.ldap.is_none() {
+        return Err(Error::InvalidSync(s!(
+            "Unable to find the local or LDAP sync configuration."
+        )));
     }
+
+    let ldap = match &sync.ldap {
+        Some(config) => Some(ldap::fetch(config, warnings)?),
+        None => None,
+    };
+
+    let merge = sources::merge(sync.local.clone(), ldap, sync.precedence);
+    warnings.extend(merge.conflicts.iter().cloned());
+
+    let mut root = merge.root;
+    root.home_policy = sync.home_policy.clone();
+
+    Ok(root)
 }
 
 /**
-Read and parse system user and group files into usable strucs.\
-Will exit the program on error.
+Read and parse system user and group files into usable strucs.
+
+# Parameters
+* `root`: The root of the filesystem containing the system files.
 
 # Returns
 A tuple with the first element being the list of users (`Vec<SystemUsers>`),\
 the second is the list of groups (`Vec<SystemGroup>`).
+Every entry is listed, the `Differ` only removes the ones accepted by the filter.
  */
-fn get_system_data() -> (Vec<SystemUser>, Vec<SystemGroup>) {
-    let user_list = match SystemUser::parse_file() {
-        Ok(list) => list,
-        Err(e) => {
-            println!("System user parse error: {}", e);
-            std::process::exit(2);
-        }
-    };
-
-    let group_list = match SystemGroup::parse_file() {
-        Ok(list) => list,
-        Err(e) => {
-            println!("System group parse error: {}", e);
-            std::process::exit(3);
-        }
-    };
+fn get_system_data(root: &Path) -> Result<(Vec<SystemUser>, Vec<SystemGroup>), Error> {
+    let user_list = SystemUser::parse_file(root)?;
+    let group_list = SystemGroup::parse_file(root)?;
 
-    (user_list, group_list)
+    Ok((user_list, group_list))
 }
 
 /**
@@ -0,0 +1,190 @@
+use serde::Serialize;
+
+use crate::{
+    consts,
+    differ::{Differ, GroupChange, SudoersChange, UserChange},
+    error::{Error, OperationResult, Summary},
+    sync_structs::Source,
+};
+
+/**
+The report of a run, printed as a single JSON document with the `--output json` option.
+ */
+#[derive(Debug, Default, Serialize)]
+pub struct Report {
+    pub dry_run: bool,
+    /// The warnings of the validation and of the sources.
+    pub warnings: Vec<String>,
+    /// The users, groups and drop-in to change, empty when the system is synchronized.
+    pub plan: Vec<PlanEntry>,
+    /// Every operation applied, in order.
+    pub operations: Vec<OperationResult>,
+    pub succeeded: usize,
+    pub failed: usize,
+    /// The changes were undone by restoring the snapshot taken before the sync.
+    pub rolled_back: bool,
+    /// The error ending the run, `None` when it succeeded.
+    pub error: Option<ErrorReport>,
+}
+
+/**
+A user, group or drop-in to change.
+ */
+#[derive(Debug, Serialize)]
+pub struct PlanEntry {
+    /// `add`, `update` or `remove` (`write` or `remove` for the sudoers drop-in).
+    pub action: &'static str,
+    /// `user`, `group` or `sudoers`.
+    pub kind: &'static str,
+    /// The name of the user or group, the path of the sudoers drop-in.
+    pub name: String,
+    /// Where the added or updated user or group is defined, `local` or `ldap`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub source: Option<Source>,
+    /// The field changes of an update.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub changes: Option<Changes>,
+}
+
+/**
+The field changes of a user or group.
+ */
+#[derive(Debug, Serialize)]
+#[serde(untagged)]
+pub enum Changes {
+    User(Vec<UserChange>),
+    Group(Vec<GroupChange>),
+}
+
+/**
+The error ending a run.
+ */
+#[derive(Debug, Serialize)]
+pub struct ErrorReport {
+    /// The exit code of the application.
+    pub code: i32,
+    pub message: String,
+}
+
+impl Report {
+    /**
+    Create an empty report.
+
+    # Parameters
+    * `dry_run`: `true` when nothing is applied.
+     */
+    pub fn new(dry_run: bool) -> Self {
+        Self {
+            dry_run,
+            ..Self::default()
+        }
+    }
+
+    /**
+    Set the plan of the report, in the order the changes are applied.
+
+    # Parameters
+    * `diff`: The diff between the system and the sync file.
+     */
+    pub fn set_plan(&mut self, diff: &Differ) {
+        let entry = |action, kind, name: &str, source, changes| PlanEntry {
+            action,
+            kind,
+            name: name.to_string(),
+            source,
+            changes,
+        };
+
+        let mut plan = Vec::<PlanEntry>::new();
+        plan.extend(
+            diff.remove
+                .1
+                .iter()
+                .map(|g| entry("remove", "group", &g.name, None, None)),
+        );
+        plan.extend(diff.update.1.iter().map(|update| {
+            let changes = Changes::Group(update.changes.clone());
+            entry(
+                "update",
+                "group",
+                &update.group.name,
+                Some(update.group.source),
+                Some(changes),
+            )
+        }));
+        plan.extend(
+            diff.add
+                .1
+                .iter()
+                .map(|g| entry("add", "group", &g.name, Some(g.source), None)),
+        );
+        plan.extend(
+            diff.remove
+                .0
+                .iter()
+                .map(|u| entry("remove", "user", &u.username, None, None)),
+        );
+        plan.extend(diff.update.0.iter().map(|update| {
+            let changes = Changes::User(update.changes.clone());
+            entry(
+                "update",
+                "user",
+                &update.user.username,
+                Some(update.user.source),
+                Some(changes),
+            )
+        }));
+        plan.extend(
+            diff.add
+                .0
+                .iter()
+                .map(|u| entry("add", "user", &u.username, Some(u.source), None)),
+        );
+        if let Some(change) = &diff.sudoers {
+            let action = match change {
+                SudoersChange::Write(_) => "write",
+                SudoersChange::Remove => "remove",
+            };
+            plan.push(entry(action, "sudoers", consts::SUDOERS_FILE, None, None));
+        }
+
+        self.plan = plan;
+    }
+
+    /**
+    Set the result of the operations.
+
+    # Parameters
+    * `summary`: The summary of the operations applied.
+     */
+    pub fn set_summary(&mut self, summary: &Summary) {
+        self.operations = summary.operations.clone();
+        self.succeeded = summary.succeeded;
+        self.failed = summary.failures.len();
+        self.rolled_back = summary.rolled_back;
+    }
+
+    /**
+    Set the error ending the run.
+
+    # Parameters
+    * `error`: The error.
+     */
+    pub fn set_error(&mut self, error: &Error) {
+        self.error = Some(ErrorReport {
+            code: error.exit_code(),
+            message: error.to_string(),
+        });
+    }
+
+    /**
+    Render the report.
+
+    # Returns
+    The report as a JSON document.
+     */
+    pub fn to_json(&self) -> String {
+        // The report only contains strings, numbers and booleans, it can't fail.
+        serde_json::to_string_pretty(self).unwrap_or_default()
+    }
+}
@@ -1,304 +1,601 @@
-use std::io::Write;
-use std::process::{Command, Stdio};
-use std::str;
+use std::io;
 
-use crate::consts::{
-    ADD_GROUP_CMD, ADD_USER_CMD, REMOVE_GROUP_CMD, REMOVE_USER_CMD, UPDATE_GROUP_CMD,
-    UPDATE_PASSWORDS_CMD, UPDATE_USER_CMD,
-};
+use crate::backend::{operation_error, Backend};
+use crate::error::Summary;
 use crate::s;
-use crate::sync_structs::{Group, LocalRoot, User};
+use crate::state::ManagedState;
+use crate::sync_structs::{AccountState, Group, LocalRoot, User};
 use crate::{
-    differ::Differ,
+    differ::{Differ, GroupUpdate, UserChange, UserUpdate},
     system_parser::{SystemGroup, SystemUser},
 };
 
+/**
+Print the progress of the operations, unless the backend is quiet.
+
+# Parameters
+* `$backend`: The backend applying the operations.
+* `$arg`: The format string and its arguments, like `print!`.
+ */
+macro_rules! progress {
+    ($backend:expr, $($arg:tt)*) => {
+        if !$backend.quiet() {
+            print!($($arg)*);
+        }
+    };
+}
+
 /**
 Apply to the system a diff.
 Makes sure both the system and sync file are properly synchonized.
 
 # Parameters
 * `diff`: The change to apply.
-* `system`: The current system state.
 * `sync`: The local sync information.
+* `managed`: The users and groups managed by the application, with their home policy.
+* `backend`: The backend used to modify the system.
 
-# Panic
-If one of the executed commands fail and is unable to terminate it properly.
+# Returns
+The summary of every operation applied, a failed operation doesn't stop the others.
  */
-pub fn apply_diff(diff: &Differ, system: &(Vec<SystemUser>, Vec<SystemGroup>), sync: &LocalRoot) {
+pub fn apply_diff(
+    diff: &Differ,
+    sync: &LocalRoot,
+    managed: &ManagedState,
+    backend: &mut dyn Backend,
+) -> Summary {
+    let mut summary = Summary::default();
     let (add_users, add_groups) = &diff.add;
     let (remove_users, remove_groups) = &diff.remove;
     let (update_users, update_groups) = &diff.update;
-    let (system_users, system_groups) = system;
 
     // Apply group changes
-    delete_group(remove_groups);
-    update_group(update_groups, system_groups);
-    add_group(add_groups);
-
-    // Get an updated group list.
-    let system_groups = SystemGroup::parse_file().unwrap_or(system_groups.clone());
+    delete_group(remove_groups, backend, &mut summary);
+    update_group(update_groups, backend, &mut summary);
+    add_group(add_groups, backend, &mut summary);
 
     // Apply user changes
-    delete_user(remove_users);
-    update_user(update_users, system_users, &system_groups);
-    add_user(add_users);
+    delete_user(remove_users, sync, managed, backend, &mut summary);
+    update_user(update_users, backend, &mut summary);
+    add_user(add_users, sync, backend, &mut summary);
 
     // Apply passwords
-    apply_passwords(&sync.users, &sync.encrypted);
+    apply_passwords(diff, sync, backend, &mut summary);
+
+    // Apply the sudo rules
+    apply_sudoers(diff, backend, &mut summary);
+
+    summary
 }
 
-fn delete_user(remove_users: &Vec<SystemUser>) {
+fn delete_user(
+    remove_users: &[SystemUser],
+    sync: &LocalRoot,
+    managed: &ManagedState,
+    backend: &mut dyn Backend,
+    summary: &mut Summary,
+) {
     for user in remove_users {
-        print!("Removing user {} ({})...", &user.username, &user.uid);
-        run_command(REMOVE_USER_CMD, &[&user.username], None)
+        progress!(
+            backend,
+            "Removing user {} ({})...",
+            &user.username,
+            &user.uid
+        );
+        let operation = format!("remove user {}", &user.username);
+        // The user is no longer in the sync file, its policy was recorded by the last sync.
+        let home = managed.home_policy(sync, &user.username);
+        report(
+            summary,
+            operation,
+            backend.delete_user(user, &home),
+            backend,
+        );
     }
 }
 
-fn delete_group(remove_groups: &Vec<SystemGroup>) {
+fn delete_group(remove_groups: &[SystemGroup], backend: &mut dyn Backend, summary: &mut Summary) {
     for group in remove_groups {
-        print!("Removing group {} ({})...", &group.name, &group.gid);
-        run_command(REMOVE_GROUP_CMD, &vec!["-f", &group.name], None);
+        progress!(
+            backend,
+            "Removing group {} ({})...",
+            &group.name,
+            &group.gid
+        );
+        let operation = format!("remove group {}", &group.name);
+        report(summary, operation, backend.delete_group(group), backend);
     }
 }
 
-fn add_user(add_users: &Vec<User>) {
+fn add_user(
+    add_users: &[User],
+    sync: &LocalRoot,
+    backend: &mut dyn Backend,
+    summary: &mut Summary,
+) {
     for user in add_users {
-        print!("Adding user {}...", &user.username);
+        progress!(
+            backend,
+            "Adding user {} ({})...",
+            &user.username,
+            &user.source
+        );
+
+        let operation = format!("add user {}", &user.username);
 
         // Skip user if not enough group.
-        if user.groups.len() < 1 {
-            println!("\tInvalid group list, need at least one group.");
+        if user.groups.is_empty() {
+            let error = operation_error(s!("Invalid group list, need at least one group."));
+            report(summary, operation, Err(error), backend);
             continue;
         }
 
-        // Compose arguments.
-        let mut args: Vec<String> = vec![s!("--create-home"), s!("--no-user-group")];
-
-        // Primary group.
-        let primary = &user.groups[0];
-        args.push(s!("--gid"));
-        args.push(primary.clone());
-
-        // Supplementary groups.
-        if user.groups.len() > 1 {
-            let groups_str = user.groups[1..].join(",");
-            args.push(s!("--groups"));
-            args.push(groups_str);
-        }
-
-        // UID
-        if let Some(uid) = user.uid {
-            args.push(s!("--non-unique"));
-            args.push(s!("--uid"));
-            args.push(uid.to_string());
+        let home = sync.user_home_policy(user);
+        let rst = backend.add_user(user, &home);
+        let added = rst.is_ok();
+        report(summary, operation, rst, backend);
+
+        // The SSH keys are written once the home directory exists.
+        if let Some(keys) = user.ssh_key_lines().filter(|k| added && !k.is_empty()) {
+            let change = UserChange::SshKeys {
+                add: keys,
+                remove: vec![],
+            };
+            progress!(backend, "\tUpdating {}...", change);
+            let operation = format!("update user {} {}", &user.username, change);
+            report(
+                summary,
+                operation,
+                backend.modify_user(user, &change),
+                backend,
+            );
         }
-
-        args.push(s!(user.username.clone()));
-
-        let args: Vec<&str> = args.iter().map(|a| a.as_ref()).collect();
-        run_command(ADD_USER_CMD, &args, None)
     }
 }
 
-fn add_group(add_groups: &Vec<Group>) {
+fn add_group(add_groups: &[Group], backend: &mut dyn Backend, summary: &mut Summary) {
     for group in add_groups {
-        print!("Adding group {}...", &group.name);
-
-        // Compose options
-        let mut args: Vec<String> = vec![s!("--force")];
-        if let Some(gid) = group.gid {
-            args.push(s!("--non-unique"));
-            args.push(s!("--gid"));
-            args.push(gid.to_string());
-        }
-
-        args.push(group.name.clone());
-
-        let args: Vec<&str> = args.iter().map(|a| a.as_ref()).collect();
-        run_command(ADD_GROUP_CMD, &args, None)
+        progress!(
+            backend,
+            "Adding group {} ({})...",
+            &group.name,
+            &group.source
+        );
+        let operation = format!("add group {}", &group.name);
+        report(summary, operation, backend.add_group(group), backend);
     }
 }
 
-fn update_user(
-    update_users: &Vec<User>,
-    system_users: &Vec<SystemUser>,
-    system_groups: &Vec<SystemGroup>,
-) {
-    for user in update_users {
-        let system_user = system_users
-            .iter()
-            .find(|u| user.username == u.username)
-            .unwrap();
-
-        println!("Updating user {}...", &system_user.username);
-        let mut change = false;
+fn update_user(update_users: &[UserUpdate], backend: &mut dyn Backend, summary: &mut Summary) {
+    for update in update_users {
+        let user = &update.user;
+        progress!(
+            backend,
+            "Updating user {} ({})...\n",
+            &user.username,
+            &user.source
+        );
+
+        for change in &update.changes {
+            // Passwords are applied all at once by `apply_passwords`.
+            if let UserChange::Password = change {
+                progress!(
+                    backend,
+                    "\tPassword will be updated with the password database.\n"
+                );
+                continue;
+            }
 
-        // Skip user if not enough group.
-        if user.groups.len() < 1 {
-            println!("\tInvalid group list, need at least one group.");
-            continue;
+            progress!(backend, "\tUpdating {}...", change);
+            let operation = format!("update user {} {}", &user.username, change);
+            report(
+                summary,
+                operation,
+                backend.modify_user(user, change),
+                backend,
+            );
         }
+    }
+}
 
-        // Primary group.
-        let current_primary = system_groups
-            .iter()
-            .find(|g| g.gid == system_user.gid)
-            .unwrap();
-        let sync_primary = &user.groups[0];
-        if current_primary.name != *sync_primary {
-            print!("\tUpdating primary group...");
-            change = true;
-            run_command(
-                UPDATE_USER_CMD,
-                &["--gid", sync_primary, &user.username],
-                None,
+fn update_group(update_groups: &[GroupUpdate], backend: &mut dyn Backend, summary: &mut Summary) {
+    for update in update_groups {
+        let group = &update.group;
+        progress!(
+            backend,
+            "Updating group {} ({})...\n",
+            &group.name,
+            &group.source
+        );
+
+        for change in &update.changes {
+            progress!(backend, "\tUpdating {}...", change);
+            let operation = format!("update group {} {}", &group.name, change);
+            report(
+                summary,
+                operation,
+                backend.modify_group(group, change),
+                backend,
             );
         }
+    }
+}
 
-        // Supplementary groups.
-        // Find current groups list name
-        let current_supp: Vec<String> = system_groups
+fn apply_passwords(
+    diff: &Differ,
+    sync: &LocalRoot,
+    backend: &mut dyn Backend,
+    summary: &mut Summary,
+) {
+    // Only the new users and changed passwords are written, so the date of the
+    // last password change is kept for the others.
+    // The password of a locked or disabled account is kept until it is active again.
+    // The users which couldn't be added would fail the whole batch, they are skipped.
+    let (add_users, _) = &diff.add;
+    let (update_users, _) = &diff.update;
+    let changed: Vec<&User> = add_users
+        .iter()
+        .filter(|user| user.state == AccountState::Active)
+        .filter(|user| summary.applied(&format!("add user {}", user.username)))
+        .chain(
+            update_users
+                .iter()
+                .filter(|update| update.changes.contains(&UserChange::Password))
+                .map(|update| &update.user),
+        )
+        .collect();
+
+    // The encrypted and plain text passwords are applied separately.
+    for encrypted in [true, false] {
+        let users: Vec<User> = changed
             .iter()
-            .filter_map(|g| match g.users.contains(&user.username) {
-                true => Some(g.name.clone()),
-                false => None,
-            })
+            .filter(|user| sync.is_encrypted(user) == encrypted)
+            .map(|user| (*user).clone())
             .collect();
-        // Make group strings
-        let current_supp_str = current_supp.join(",");
-        let sync_supp = user.groups[1..].join(",");
-        if sync_supp != current_supp_str {
-            print!("\tUpdating supplentary group list...");
-            change = true;
-            run_command(
-                UPDATE_USER_CMD,
-                &["--groups", &sync_supp, &user.username],
-                None,
-            );
+        if users.is_empty() {
+            continue;
         }
 
-        // UID changed
-        if let Some(uid) = user.uid {
-            if uid != system_user.uid {
-                change = true;
-                print!("\tUpdating UID {} -> {}...", &system_user.uid, uid);
-                run_command(
-                    UPDATE_USER_CMD,
-                    &["--non-unique", "--uid", &uid.to_string(), &user.username],
-                    None,
-                );
-            }
-        }
+        progress!(backend, "Updating password database...");
+        let operation = s!("update passwords");
+        report(
+            summary,
+            operation,
+            backend.set_passwords(&users, encrypted),
+            backend,
+        );
+    }
+}
 
-        // Skip message
-        if !change {
-            println!("\tSkipping user (no change).");
-        }
+fn apply_sudoers(diff: &Differ, backend: &mut dyn Backend, summary: &mut Summary) {
+    if let Some(change) = &diff.sudoers {
+        progress!(backend, "Updating sudoers drop-in...");
+        let operation = format!("update sudoers drop-in ({})", change);
+        report(summary, operation, backend.set_sudoers(change), backend);
     }
 }
 
-fn update_group(update_groups: &Vec<Group>, system_groups: &Vec<SystemGroup>) {
-    for group in update_groups {
-        // Find the system user.
-        let system_group = system_groups.iter().find(|g| group.name == g.name).unwrap();
-
-        // Print and setup.
-        println!("Updating group {}...", &system_group.name);
-        let mut change = false;
-
-        // Apply change to GID.
-        if let Some(gid) = group.gid {
-            if system_group.gid != gid {
-                change = true;
-                print!("\tUpdating GID {} -> {}...", &system_group.gid, &gid);
-                run_command(
-                    UPDATE_GROUP_CMD,
-                    &vec!["--non-unique", "--gid", &gid.to_string(), &group.name],
-                    None,
-                )
-            }
-        }
+/**
+Print and record the result of an operation.
 
-        // Skip message
-        if !change {
-            println!("\tSkipping group (no change).");
-        }
+# Parameters
+* `summary`: The summary recording the results.
+* `operation`: The description of the operation.
+* `rst`: The result of the operation.
+* `backend`: The backend which applied the operation.
+ */
+fn report(
+    summary: &mut Summary,
+    operation: String,
+    rst: io::Result<()>,
+    backend: &mut dyn Backend,
+) {
+    match &rst {
+        // The dry-run backend prints the operation itself.
+        Ok(_) if backend.dry_run() => {}
+        Ok(_) => progress!(backend, "Success\n"),
+        Err(e) => progress!(backend, "Failed\n==========\n{}\n==========\n", e),
     }
+
+    summary.record(operation, rst, backend.take_commands());
 }
 
-fn apply_passwords(sync: &Vec<User>, encrypted: &bool) {
-    print!("Updating password database...");
-    let mut stdin_buf = String::new();
-    for user in sync {
-        stdin_buf.push_str(&format!("{}:{}\n", &user.username, &user.password))
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        backend::Memory, filter::IdFilter, ssh::AuthorizedKeys, system_parser::ShadowAging,
+    };
+
+    /**
+    Parse the local section of a sync file.
+
+    # Parameters
+    * `json`: The local section, in JSON.
+     */
+    fn local_root(json: &str) -> LocalRoot {
+        let mut sync: LocalRoot = serde_json::from_str(json).unwrap();
+        sync.resolve_members();
+        sync
     }
 
-    let mut args: Vec<String> = vec![];
-    if *encrypted {
-        args.push(s!("--encrypted"));
+    /**
+    Build a system user.
+
+    # Parameters
+    * `username`: The name of the user.
+    * `uid`: The id of the user.
+    * `gid`: The id of the primary group of the user.
+     */
+    fn system_user(username: &str, uid: u32, gid: u32) -> SystemUser {
+        SystemUser {
+            username: s!(username),
+            hash: s!("$6$salt$hash"),
+            uid,
+            gid,
+            gecos: String::new(),
+            home: format!("/home/{}", username),
+            shell: s!("/bin/sh"),
+            aging: ShadowAging::default(),
+            authorized_keys: AuthorizedKeys::default(),
+        }
     }
 
-    let args: Vec<&str> = args.iter().map(|a| a.as_ref()).collect();
-    run_command(UPDATE_PASSWORDS_CMD, &args, Some(&stdin_buf));
-}
+    /**
+    Build a system group.
+
+    # Parameters
+    * `name`: The name of the group.
+    * `gid`: The id of the group.
+    * `users`: The supplementary members of the group.
+     */
+    fn system_group(name: &str, gid: u32, users: &[&str]) -> SystemGroup {
+        SystemGroup {
+            name: s!(name),
+            gid,
+            users: users.iter().map(|u| s!(*u)).collect(),
+        }
+    }
 
-/**
-Run the specified command with given arguments and stdin buffer.
+    /**
+    Compare the in-memory system with the sync file.
 
-# Parameters
-* `command`: A string representation of the command to run.
-* `args`: A list of arguments to be passed to the command.
-* `stdin_buf`: The standard input to be written once the command is spawned.
+    # Parameters
+    * `sync`: The local sync information.
+    * `backend`: The in-memory system.
+     */
+    fn diff(sync: &LocalRoot, backend: &Memory) -> Differ {
+        let system = (backend.users.clone(), backend.groups.clone());
+        Differ::new(sync, &system, None, None, &IdFilter::default())
+    }
 
-# Panic
-If the command fail and is unable to kill the child process.
- */
-fn run_command(command: &str, args: &[&str], stdin_buf: Option<&str>) {
-    let mut cmd = Command::new(command);
-    cmd.args(args).stdout(Stdio::piped()).stderr(Stdio::piped());
+    /**
+    Synchronize the in-memory system with the sync file.
 
-    // Only pipe stdin when needed.
-    if stdin_buf.is_some() {
-        cmd.stdin(Stdio::piped());
+    # Parameters
+    * `sync`: The local sync information.
+    * `backend`: The in-memory system.
+     */
+    fn sync(sync: &LocalRoot, backend: &mut Memory) -> Summary {
+        let diff = diff(sync, backend);
+        apply_diff(&diff, sync, &ManagedState::default(), backend)
     }
 
-    let child_rst = cmd.spawn();
-
-    match child_rst {
-        Ok(mut child) => {
-            // Write to stdin when a buffer is present.
-            if let Some(stdin_buf) = stdin_buf {
-                if let Some(mut stdin) = child.stdin.take() {
-                    let write_rst = stdin
-                        .write_all(&stdin_buf.as_bytes())
-                        .and_then(|_| stdin.flush());
-
-                    if let Err(e) = write_rst {
-                        println!("STDIN error; {}", e);
-                        child.kill().unwrap();
-                    }
-                }
-            }
+    #[test]
+    fn system_group_outside_the_filter_is_synchronized() {
+        let sync_file = local_root(
+            r#"{
+                "encrypted": true,
+                "users": [
+                    { "username": "alice", "password": "$6$salt$hash", "groups": ["staff", "sudo"] }
+                ],
+                "groups": [{ "name": "staff" }, { "name": "sudo" }]
+            }"#,
+        );
+        let system = (
+            vec![system_user("alice", 1000, 1000)],
+            vec![
+                system_group("sudo", 27, &[]),
+                system_group("staff", 1000, &[]),
+            ],
+        );
+        let mut backend = Memory::new(system, &IdFilter::default(), true);
+
+        let summary = sync(&sync_file, &mut backend);
+        assert!(summary.failures.is_empty(), "{}", summary);
+        assert_eq!(backend.groups[0].gid, 27);
+        assert_eq!(backend.groups[0].users, vec![s!("alice")]);
+
+        let diff = diff(&sync_file, &backend);
+        assert!(diff.is_empty(), "{:?}", diff.summary());
+    }
 
-            // Wait for command to finish and print result.
-            match child.wait_with_output() {
-                Ok(output) => {
-                    if output.status.success() {
-                        println!("Success");
-                    } else {
-                        println!("Failed");
-                        println!(
-                            "==========\n{} {:?}\n{}\n==========",
-                            command,
-                            args,
-                            String::from_utf8_lossy(&output.stderr)
-                        );
-                    }
-                }
-                Err(err) => println!("Execution error: {}", err),
-            }
-        }
-        Err(err) => println!("Remove command failed: {}", err),
+    #[test]
+    fn users_and_groups_are_added_updated_and_removed() {
+        let sync_file = local_root(
+            r#"{
+                "encrypted": true,
+                "users": [
+                    {
+                        "username": "alice",
+                        "password": "$6$salt$alice",
+                        "groups": ["staff", "devs"],
+                        "gecos": "Alice",
+                        "shell": "/bin/bash"
+                    },
+                    { "username": "carol", "password": "$6$salt$carol", "groups": ["devs"] }
+                ],
+                "groups": [{ "name": "staff" }, { "name": "devs" }]
+            }"#,
+        );
+        let system = (
+            vec![
+                system_user("alice", 1000, 1000),
+                system_user("bob", 1001, 1000),
+            ],
+            vec![
+                system_group("staff", 1000, &[]),
+                system_group("old", 1001, &["alice", "bob"]),
+            ],
+        );
+        let mut backend = Memory::new(system, &IdFilter::default(), true);
+
+        let first = diff(&sync_file, &backend);
+        let names = |users: &[User]| users.iter().map(|u| u.username.clone()).collect::<Vec<_>>();
+        assert_eq!(names(&first.add.0), vec![s!("carol")]);
+        assert_eq!(first.add.1[0].name, "devs");
+        assert_eq!(
+            first.update.0[0].changes,
+            vec![
+                UserChange::SupplementaryGroups {
+                    add: vec![s!("devs")],
+                    remove: vec![s!("old")]
+                },
+                UserChange::Gecos {
+                    from: String::new(),
+                    to: s!("Alice")
+                },
+                UserChange::Shell {
+                    from: s!("/bin/sh"),
+                    to: s!("/bin/bash")
+                },
+                UserChange::Password
+            ]
+        );
+        assert_eq!(first.remove.0[0].username, "bob");
+        assert_eq!(first.remove.1[0].name, "old");
+        assert_eq!(first.count(), 5);
+
+        let summary = sync(&sync_file, &mut backend);
+        assert!(summary.failures.is_empty(), "{}", summary);
+
+        let names: Vec<&str> = backend.users.iter().map(|u| u.username.as_str()).collect();
+        assert_eq!(names, vec!["alice", "carol"]);
+        let names: Vec<&str> = backend.groups.iter().map(|g| g.name.as_str()).collect();
+        assert_eq!(names, vec!["staff", "devs"]);
+        // The ids of the removed entries are reused.
+        let devs = &backend.groups[1];
+        assert_eq!((devs.gid, devs.users.clone()), (1001, vec![s!("alice")]));
+        let alice = &backend.users[0];
+        assert_eq!(
+            (
+                alice.gecos.as_str(),
+                alice.shell.as_str(),
+                alice.hash.as_str()
+            ),
+            ("Alice", "/bin/bash", "$6$salt$alice")
+        );
+        let carol = &backend.users[1];
+        assert_eq!((carol.uid, carol.gid), (1001, 1001));
+
+        // A second sync has nothing left to apply.
+        assert!(diff(&sync_file, &backend).is_empty());
+    }
+
+    #[test]
+    fn failed_operations_are_recorded() {
+        let sync_file = local_root(
+            r#"{
+                "encrypted": true,
+                "users": [
+                    { "username": "alice", "password": "$6$salt$alice", "groups": ["staff"] },
+                    { "username": "dave", "password": "$6$salt$dave", "groups": ["missing"] }
+                ],
+                "groups": [{ "name": "staff" }]
+            }"#,
+        );
+        let system = (vec![], vec![system_group("staff", 1000, &[])]);
+        let mut backend = Memory::new(system, &IdFilter::default(), true);
+
+        let summary = sync(&sync_file, &mut backend);
+        assert_eq!(summary.failures.len(), 1, "{}", summary);
+        let failure = &summary.failures[0];
+        assert_eq!(failure.operation, "add user dave");
+        assert!(failure
+            .error
+            .to_string()
+            .contains("group 'missing' does not exist"));
+        // The password of the other user is still applied.
+        assert!(summary.applied("update passwords"));
+        assert_eq!(backend.users[0].hash, "$6$salt$alice");
+        assert!(summary.into_result().is_err());
+
+        // The failed user is still to be added, the other one is synchronized.
+        let diff = diff(&sync_file, &backend);
+        assert_eq!(diff.add.0.len(), 1);
+        assert_eq!(diff.add.0[0].username, "dave");
+        assert_eq!(diff.count(), 1);
+    }
+
+    #[test]
+    fn account_without_password_is_synchronized() {
+        let sync_file = local_root(
+            r#"{
+                "encrypted": true,
+                "users": [
+                    { "username": "alice", "password": "!", "groups": ["staff"] },
+                    { "username": "bob", "password": "*", "groups": ["staff"] }
+                ],
+                "groups": [{ "name": "staff" }]
+            }"#,
+        );
+        let mut alice = system_user("alice", 1000, 1000);
+        alice.hash = s!("!");
+        let system = (vec![alice], vec![system_group("staff", 1000, &[])]);
+        let mut backend = Memory::new(system, &IdFilter::default(), true);
+
+        // The lock of alice is kept, only bob is added.
+        let first = diff(&sync_file, &backend);
+        assert_eq!(first.summary(), vec![s!("add user bob from local")]);
+
+        let summary = sync(&sync_file, &mut backend);
+        assert!(summary.failures.is_empty(), "{}", summary);
+        let diff = diff(&sync_file, &backend);
+        assert!(diff.is_empty(), "{:?}", diff.summary());
+    }
+
+    #[test]
+    fn only_added_entries_are_managed_unless_adopted() {
+        let sync_file = local_root(
+            r#"{
+                "encrypted": true,
+                "users": [
+                    { "username": "alice", "password": "$6$salt$alice", "groups": ["staff"] },
+                    { "username": "bob", "password": "$6$salt$hash", "groups": ["staff"] },
+                    { "username": "dave", "password": "$6$salt$dave", "groups": ["missing"] }
+                ],
+                "groups": [{ "name": "staff" }]
+            }"#,
+        );
+        // Bob and the staff group were created by hand.
+        let system = (
+            vec![system_user("bob", 1000, 1000)],
+            vec![system_group("staff", 1000, &["bob"])],
+        );
+        let mut backend = Memory::new(system, &IdFilter::default(), true);
+
+        let diff = diff(&sync_file, &backend);
+        let summary = apply_diff(&diff, &sync_file, &ManagedState::default(), &mut backend);
+        assert_eq!(summary.failures[0].operation, "add user dave");
+        let system = (backend.users.clone(), backend.groups.clone());
+
+        let managed =
+            ManagedState::default().after_sync(&sync_file, &diff, &summary, false, &system);
+        assert_eq!(managed.users.iter().collect::<Vec<_>>(), vec!["alice"]);
+        assert!(managed.groups.is_empty());
+
+        let adopted =
+            ManagedState::default().after_sync(&sync_file, &diff, &summary, true, &system);
+        assert_eq!(
+            adopted.users.iter().collect::<Vec<_>>(),
+            vec!["alice", "bob"]
+        );
+        assert_eq!(adopted.groups.iter().collect::<Vec<_>>(), vec!["staff"]);
+
+        // The accounts created by hand are left untouched once they leave the sync file.
+        let empty = local_root(r#"{ "encrypted": true, "users": [], "groups": [] }"#);
+        let system = (backend.users.clone(), backend.groups.clone());
+        let removal = Differ::new(&empty, &system, Some(&managed), None, &IdFilter::default());
+        assert_eq!(removal.summary(), vec![s!("remove user alice")]);
     }
 }
@@ -0,0 +1,366 @@
+use std::{
+    fs, io,
+    os::unix::fs::{self as unix_fs, MetadataExt, PermissionsExt},
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{consts, date, system_parser};
+
+/// The files saved in a snapshot.
+const SNAPSHOT_FILES: &[&str] = &[
+    consts::USER_FILE,
+    consts::GROUP_FILE,
+    consts::PASSWORD_FILE,
+    consts::GROUP_PASSWORD_FILE,
+    consts::SUDOERS_FILE,
+    consts::STATE_FILE,
+];
+/// The name of the description of the snapshot, in the snapshot directory.
+const MANIFEST_FILE: &str = "manifest.json";
+
+/**
+A copy of the user database files (and of the managed state) taken before a sync.
+
+Only the snapshot of the last sync which changed the system is kept, it is used to roll back
+a failed sync or to manually revert the last sync with the `rollback` command.
+A new snapshot is pending until it is kept, so a sync applying nothing doesn't replace the previous one.
+The home directories created or moved during the sync are not part of the snapshot.
+ */
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Snapshot {
+    /// The time the snapshot was taken, in seconds since 1970-01-01.
+    pub taken_at: u64,
+    pub files: Vec<SnapshotFile>,
+    /// The directory containing the copies of the files.
+    #[serde(skip)]
+    dir: PathBuf,
+}
+
+/**
+A file of a snapshot.
+ */
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SnapshotFile {
+    /// The absolute path of the file on the live system (e.g. `/etc/passwd`).
+    pub path: String,
+    /// `false` when the file didn't exist, it is then removed on rollback.
+    pub exists: bool,
+}
+
+impl Snapshot {
+    /**
+    Take a pending snapshot of the user database files, the previous one is kept until `keep` is called.
+
+    # Parameters
+    * `root`: The root of the filesystem containing the files.
+     */
+    pub fn take(root: &Path) -> io::Result<Self> {
+        let dir = pending_dir(root);
+        match fs::remove_dir_all(&dir) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
+        fs::create_dir_all(&dir)?;
+        fs::set_permissions(&dir, fs::Permissions::from_mode(0o700))?;
+
+        let mut files = Vec::<SnapshotFile>::new();
+        for file in SNAPSHOT_FILES {
+            let path = system_parser::system_path(root, file);
+            let exists = path.exists();
+            if exists {
+                copy_file(&path, &dir.join(snapshot_name(file)))?;
+            }
+            files.push(SnapshotFile {
+                path: file.to_string(),
+                exists,
+            });
+        }
+
+        let snapshot = Self {
+            taken_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |d| d.as_secs()),
+            files,
+            dir,
+        };
+        let manifest = serde_json::to_string_pretty(&snapshot)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        fs::write(snapshot.dir.join(MANIFEST_FILE), manifest)?;
+
+        Ok(snapshot)
+    }
+
+    /**
+    Replace the previous snapshot with a pending one, once the sync changed the system.
+
+    # Parameters
+    * `root`: The root of the filesystem containing the snapshot.
+     */
+    pub fn keep(self, root: &Path) -> io::Result<()> {
+        let dir = system_parser::system_path(root, consts::SNAPSHOT_DIR);
+        match fs::remove_dir_all(&dir) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
+        fs::rename(&self.dir, &dir)
+    }
+
+    /**
+    Remove a pending snapshot, the sync didn't change the system so the previous one is kept.
+     */
+    pub fn discard(self) -> io::Result<()> {
+        fs::remove_dir_all(&self.dir)
+    }
+
+    /**
+    Read the last snapshot.
+
+    # Parameters
+    * `root`: The root of the filesystem containing the snapshot.
+
+    # Returns
+    The snapshot, or a `NotFound` error when no snapshot was taken.
+     */
+    pub fn load(root: &Path) -> io::Result<Self> {
+        let dir = system_parser::system_path(root, consts::SNAPSHOT_DIR);
+        let manifest = fs::read_to_string(dir.join(MANIFEST_FILE)).map_err(|e| match e.kind() {
+            io::ErrorKind::NotFound => io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("no snapshot found in {}", dir.display()),
+            ),
+            _ => e,
+        })?;
+
+        let snapshot: Self = serde_json::from_str(&manifest)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        Ok(Self { dir, ..snapshot })
+    }
+
+    /**
+    Restore the files of the snapshot.
+    Each file is restored with an atomic rename, the files which didn't exist are removed.
+
+    # Parameters
+    * `root`: The root of the filesystem containing the files.
+
+    # Returns
+    The path of every restored file.
+     */
+    pub fn restore(&self, root: &Path) -> io::Result<Vec<PathBuf>> {
+        let dir = &self.dir;
+
+        let mut restored = Vec::<PathBuf>::new();
+        for file in &self.files {
+            let path = system_parser::system_path(root, &file.path);
+            if file.exists {
+                let tmp = path.with_file_name(format!(
+                    "{}+",
+                    path.file_name().unwrap_or_default().to_string_lossy()
+                ));
+                copy_file(&dir.join(snapshot_name(&file.path)), &tmp)?;
+                fs::rename(&tmp, &path)?;
+            } else {
+                match fs::remove_file(&path) {
+                    Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                    rst => rst?,
+                }
+            }
+            restored.push(path);
+        }
+
+        Ok(restored)
+    }
+
+    /**
+    Get the date the snapshot was taken.
+
+    # Returns
+    The ISO 8601 date (`YYYY-MM-DD`).
+     */
+    pub fn date(&self) -> String {
+        date::format_date((self.taken_at / 86400) as i64)
+    }
+}
+
+/**
+Get the directory of a pending snapshot, next to the directory of the last one.
+
+# Parameters
+* `root`: The root of the filesystem containing the snapshot.
+ */
+fn pending_dir(root: &Path) -> PathBuf {
+    system_parser::system_path(root, consts::SNAPSHOT_DIR).with_extension("new")
+}
+
+/**
+Get the name of a file in the snapshot directory.
+
+# Parameters
+* `file`: The absolute path of the file on the live system.
+ */
+fn snapshot_name(file: &str) -> String {
+    file.trim_start_matches('/').replace('/', "_")
+}
+
+/**
+Copy a file with its permissions and owner, the copy is flushed to the disk.
+
+# Parameters
+* `from`: The file to copy.
+* `to`: The destination, replaced when it exists.
+ */
+fn copy_file(from: &Path, to: &Path) -> io::Result<()> {
+    let metadata = fs::metadata(from)?;
+    fs::copy(from, to)?;
+    unix_fs::chown(to, Some(metadata.uid()), Some(metadata.gid()))?;
+    fs::File::open(to)?.sync_all()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::process;
+
+    use super::*;
+
+    /**
+    Create a filesystem root with a user and group file, removed by the test once done.
+
+    # Parameters
+    * `name`: The name of the test, so the tests don't share a root.
+     */
+    fn temp_root(name: &str) -> PathBuf {
+        let root = std::env::temp_dir().join(format!("user-sync-{}-{}", name, process::id()));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(root.join("etc")).unwrap();
+        write(
+            &root,
+            consts::USER_FILE,
+            "root:x:0:0:root:/root:/bin/bash\n",
+        );
+        write(&root, consts::GROUP_FILE, "root:x:0:\n");
+        root
+    }
+
+    /**
+    Write a file of the filesystem root.
+
+    # Parameters
+    * `root`: The root of the filesystem.
+    * `file`: The path of the file on the live system.
+    * `content`: The content of the file.
+     */
+    fn write(root: &Path, file: &str, content: &str) {
+        let path = system_parser::system_path(root, file);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, content).unwrap();
+    }
+
+    /**
+    Read a file of the filesystem root.
+
+    # Parameters
+    * `root`: The root of the filesystem.
+    * `file`: The path of the file on the live system.
+     */
+    fn read(root: &Path, file: &str) -> Option<String> {
+        fs::read_to_string(system_parser::system_path(root, file)).ok()
+    }
+
+    #[test]
+    fn restore_reverts_the_files_and_removes_the_new_ones() {
+        let root = temp_root("snapshot-restore");
+        let snapshot = Snapshot::take(&root).unwrap();
+        let saved: Vec<_> = snapshot.files.iter().filter(|f| f.exists).collect();
+        assert_eq!(saved.len(), 2);
+        assert_eq!(snapshot.date(), date::format_date(date::today()));
+
+        write(
+            &root,
+            consts::USER_FILE,
+            "alice:x:1000:1000::/home/alice:/bin/sh\n",
+        );
+        write(&root, consts::SUDOERS_FILE, "alice ALL=(ALL) ALL\n");
+        let restored = snapshot.restore(&root).unwrap();
+
+        assert_eq!(
+            read(&root, consts::USER_FILE).as_deref(),
+            Some("root:x:0:0:root:/root:/bin/bash\n")
+        );
+        assert_eq!(
+            read(&root, consts::GROUP_FILE).as_deref(),
+            Some("root:x:0:\n")
+        );
+        assert_eq!(read(&root, consts::SUDOERS_FILE), None);
+        // The files which still don't exist aren't reported.
+        assert_eq!(
+            restored,
+            [consts::USER_FILE, consts::GROUP_FILE, consts::SUDOERS_FILE]
+                .iter()
+                .map(|file| system_parser::system_path(&root, file))
+                .collect::<Vec<_>>()
+        );
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn kept_snapshot_replaces_the_previous_one() {
+        let root = temp_root("snapshot-keep");
+        assert_eq!(
+            Snapshot::load(&root).unwrap_err().kind(),
+            io::ErrorKind::NotFound
+        );
+
+        let pending = Snapshot::take(&root).unwrap();
+        // A pending snapshot can't be restored by the `rollback` command.
+        assert!(Snapshot::load(&root).is_err());
+        let mode = fs::metadata(&pending.dir).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o700);
+        pending.keep(&root).unwrap();
+        assert!(!pending_dir(&root).exists());
+
+        write(
+            &root,
+            consts::USER_FILE,
+            "alice:x:1000:1000::/home/alice:/bin/sh\n",
+        );
+        Snapshot::take(&root).unwrap().keep(&root).unwrap();
+        write(
+            &root,
+            consts::USER_FILE,
+            "bob:x:1001:1001::/home/bob:/bin/sh\n",
+        );
+
+        // The last snapshot is restored, from its final directory.
+        let snapshot = Snapshot::load(&root).unwrap();
+        snapshot.restore(&root).unwrap();
+        assert_eq!(
+            read(&root, consts::USER_FILE).as_deref(),
+            Some("alice:x:1000:1000::/home/alice:/bin/sh\n")
+        );
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn discarded_snapshot_keeps_the_previous_one() {
+        let root = temp_root("snapshot-discard");
+        Snapshot::take(&root).unwrap().keep(&root).unwrap();
+
+        write(
+            &root,
+            consts::USER_FILE,
+            "alice:x:1000:1000::/home/alice:/bin/sh\n",
+        );
+        Snapshot::take(&root).unwrap().discard().unwrap();
+        assert!(!pending_dir(&root).exists());
+
+        Snapshot::load(&root).unwrap().restore(&root).unwrap();
+        assert_eq!(
+            read(&root, consts::USER_FILE).as_deref(),
+            Some("root:x:0:0:root:/root:/bin/bash\n")
+        );
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,298 @@
+use crate::sync_structs::{Group, HomePolicy, LocalRoot, Source, User};
+
+/**
+The result of the merge of the sync sources.
+ */
+#[derive(Debug)]
+pub struct Merge {
+    /// The users and groups to synchronize.
+    pub root: LocalRoot,
+    /// The conflicts found between the sources.
+    pub conflicts: Vec<String>,
+}
+
+/**
+Merge the sync sources into the users and groups to synchronize.
+
+When a user or group is defined by both sources, the definition of the source with
+precedence is used. The users and groups with the same UID or GID but a different name
+are kept, but reported as conflicts.
+
+# Parameters
+* `local`: The local users and groups, if any.
+* `ldap`: The users and groups fetched from LDAP, if any.
+* `precedence`: The source winning on conflicts.
+
+# Returns
+The merged users and groups, with the conflicts found.
+ */
+pub fn merge(local: Option<LocalRoot>, ldap: Option<LocalRoot>, precedence: Source) -> Merge {
+    let sources = match precedence {
+        Source::Local => [local, ldap],
+        Source::Ldap => [ldap, local],
+    };
+
+    let mut conflicts = Vec::<String>::new();
+    let mut encrypted: Option<bool> = None;
+    let mut users = Vec::<User>::new();
+    let mut groups = Vec::<Group>::new();
+
+    for root in sources.iter().flatten() {
+        encrypted = encrypted.or(Some(root.encrypted));
+
+        for group in &root.groups {
+            // Duplicated groups within a source are reported by the validation.
+            if let Some(existing) = groups
+                .iter()
+                .find(|g| g.source != group.source && g.name == group.name)
+            {
+                if existing.gid != group.gid
+                    || existing.members != group.members
+                    || existing.sudo != group.sudo
+                {
+                    conflicts.push(format!(
+                        "Group `{}` is defined by {} and {}, the {} definition is used.",
+                        group.name, existing.source, group.source, existing.source
+                    ));
+                }
+                continue;
+            }
+
+            // Duplicated GIDs within a source are reported by the validation.
+            if let Some(existing) = groups
+                .iter()
+                .find(|g| g.source != group.source && g.gid.is_some() && g.gid == group.gid)
+            {
+                conflicts.push(format!(
+                    "GID {} is used by `{}` ({}) and `{}` ({}).",
+                    group.gid.unwrap_or_default(),
+                    existing.name,
+                    existing.source,
+                    group.name,
+                    group.source
+                ));
+            }
+
+            groups.push(group.clone());
+        }
+
+        for user in &root.users {
+            // Duplicated users within a source are reported by the validation.
+            if let Some(existing) = users
+                .iter()
+                .find(|u| u.source != user.source && u.username == user.username)
+            {
+                let same = existing.uid == user.uid
+                    && existing.groups == user.groups
+                    && existing.password == user.password
+                    && existing.gecos == user.gecos
+                    && existing.home == user.home
+                    && existing.shell == user.shell
+                    && existing.aging == user.aging
+                    && existing.state == user.state
+                    && existing.home_policy == user.home_policy
+                    && existing.ssh_keys == user.ssh_keys
+                    && existing.ssh_keys_mode == user.ssh_keys_mode
+                    && existing.sudo == user.sudo;
+                if !same {
+                    conflicts.push(format!(
+                        "User `{}` is defined by {} and {}, the {} definition is used.",
+                        user.username, existing.source, user.source, existing.source
+                    ));
+                }
+                continue;
+            }
+
+            // Duplicated UIDs within a source are reported by the validation.
+            if let Some(existing) = users
+                .iter()
+                .find(|u| u.source != user.source && u.uid.is_some() && u.uid == user.uid)
+            {
+                conflicts.push(format!(
+                    "UID {} is used by `{}` ({}) and `{}` ({}).",
+                    user.uid.unwrap_or_default(),
+                    existing.username,
+                    existing.source,
+                    user.username,
+                    user.source
+                ));
+            }
+
+            // Keep the encryption of the user source once merged.
+            let mut user = user.clone();
+            user.encrypted = Some(root.is_encrypted(&user));
+            users.push(user);
+        }
+    }
+
+    Merge {
+        root: LocalRoot {
+            encrypted: encrypted.unwrap_or_default(),
+            users,
+            groups,
+            home_policy: HomePolicy::default(),
+        },
+        conflicts,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::s;
+
+    /**
+    Parse the users and groups of a source.
+
+    # Parameters
+    * `json`: The users and groups, in JSON.
+    * `source`: The source of the users and groups.
+     */
+    fn root(json: &str, source: Source) -> LocalRoot {
+        let mut root: LocalRoot = serde_json::from_str(json).unwrap();
+        root.users.iter_mut().for_each(|u| u.source = source);
+        root.groups.iter_mut().for_each(|g| g.source = source);
+        root
+    }
+
+    /**
+    The local users and groups, alice is also defined by LDAP with another UID.
+     */
+    fn local() -> LocalRoot {
+        root(
+            r#"{
+                "encrypted": false,
+                "users": [{ "username": "alice", "uid": 1000, "password": "secret", "groups": ["staff"] }],
+                "groups": [{ "name": "staff", "gid": 1000 }]
+            }"#,
+            Source::Local,
+        )
+    }
+
+    /**
+    The LDAP users and groups, staff is defined like the local one.
+     */
+    fn ldap() -> LocalRoot {
+        root(
+            r#"{
+                "encrypted": true,
+                "users": [
+                    { "username": "alice", "uid": 2000, "password": "$6$salt$alice", "groups": ["staff"] },
+                    { "username": "bob", "uid": 2001, "password": "$6$salt$bob", "groups": ["devs"] }
+                ],
+                "groups": [{ "name": "staff", "gid": 1000 }, { "name": "devs", "gid": 2000 }]
+            }"#,
+            Source::Ldap,
+        )
+    }
+
+    /**
+    Get the name, UID and source of every user.
+
+    # Parameters
+    * `root`: The merged users and groups.
+     */
+    fn users(root: &LocalRoot) -> Vec<(&str, Option<u32>, Source)> {
+        root.users
+            .iter()
+            .map(|u| (u.username.as_str(), u.uid, u.source))
+            .collect()
+    }
+
+    #[test]
+    fn local_definitions_win_with_local_precedence() {
+        let merge = merge(Some(local()), Some(ldap()), Source::Local);
+
+        assert_eq!(
+            users(&merge.root),
+            vec![
+                ("alice", Some(1000), Source::Local),
+                ("bob", Some(2001), Source::Ldap)
+            ]
+        );
+        let groups: Vec<_> = merge
+            .root
+            .groups
+            .iter()
+            .map(|g| (g.name.as_str(), g.source))
+            .collect();
+        assert_eq!(
+            groups,
+            vec![("staff", Source::Local), ("devs", Source::Ldap)]
+        );
+        // The identical definitions of staff aren't a conflict.
+        assert_eq!(
+            merge.conflicts,
+            vec![s!(
+                "User `alice` is defined by local and ldap, the local definition is used."
+            )]
+        );
+        // Each user keeps the encryption of its source.
+        assert!(!merge.root.encrypted);
+        assert_eq!(merge.root.users[0].encrypted, Some(false));
+        assert_eq!(merge.root.users[1].encrypted, Some(true));
+    }
+
+    #[test]
+    fn ldap_definitions_win_with_ldap_precedence() {
+        let merge = merge(Some(local()), Some(ldap()), Source::Ldap);
+
+        assert_eq!(
+            users(&merge.root),
+            vec![
+                ("alice", Some(2000), Source::Ldap),
+                ("bob", Some(2001), Source::Ldap)
+            ]
+        );
+        assert_eq!(merge.root.users[0].password, "$6$salt$alice");
+        assert_eq!(
+            merge.conflicts,
+            vec![s!(
+                "User `alice` is defined by ldap and local, the ldap definition is used."
+            )]
+        );
+        assert!(merge.root.encrypted);
+    }
+
+    #[test]
+    fn id_conflicts_are_reported() {
+        let local = root(
+            r#"{
+                "encrypted": true,
+                "users": [{ "username": "carol", "uid": 2001, "password": "!", "groups": ["ops"] }],
+                "groups": [{ "name": "ops", "gid": 2000 }, { "name": "staff", "gid": 1500 }]
+            }"#,
+            Source::Local,
+        );
+
+        let merge = merge(Some(local), Some(ldap()), Source::Local);
+        assert_eq!(
+            merge.conflicts,
+            vec![
+                s!("Group `staff` is defined by local and ldap, the local definition is used."),
+                s!("GID 2000 is used by `ops` (local) and `devs` (ldap)."),
+                s!("UID 2001 is used by `carol` (local) and `bob` (ldap)."),
+            ]
+        );
+        // The entries with the same id are kept, the validation of the ids reports them.
+        assert_eq!(
+            users(&merge.root),
+            vec![
+                ("carol", Some(2001), Source::Local),
+                ("alice", Some(2000), Source::Ldap),
+                ("bob", Some(2001), Source::Ldap)
+            ]
+        );
+        assert_eq!(merge.root.groups.len(), 3);
+    }
+
+    #[test]
+    fn single_source_is_kept_as_is() {
+        let merge = merge(None, Some(ldap()), Source::Local);
+
+        assert!(merge.conflicts.is_empty());
+        assert_eq!(merge.root.users.len(), 2);
+        assert_eq!(merge.root.groups.len(), 2);
+        assert!(merge.root.encrypted);
+    }
+}
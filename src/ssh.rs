@@ -0,0 +1,380 @@
+use std::{
+    ffi::CString,
+    fs::{self, File, OpenOptions},
+    io::{self, Read, Write},
+    os::unix::{
+        ffi::OsStrExt,
+        fs::{self as unix_fs, DirBuilderExt, OpenOptionsExt, PermissionsExt},
+        io::{AsRawFd, FromRawFd},
+    },
+    path::{Path, PathBuf},
+};
+
+use crate::{backend::operation_error, consts, s, sync_structs::AuthorizedKeysMode, system_parser};
+
+/// The first line of the block of keys managed by the application.
+const BEGIN_MARKER: &str = "# BEGIN user-sync managed keys";
+/// The last line of the block of keys managed by the application.
+const END_MARKER: &str = "# END user-sync managed keys";
+/// The first line of a file entirely managed by the application.
+const MANAGED_HEADER: &str = "# Managed by user-sync, the keys added by hand are removed.";
+
+/**
+The content of an `authorized_keys` file.
+ */
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct AuthorizedKeys {
+    /// Every line of the file, comments included.
+    pub lines: Vec<String>,
+}
+
+impl AuthorizedKeys {
+    /**
+    Parse the content of an `authorized_keys` file.
+
+    # Parameters
+    * `content`: The content of the file.
+     */
+    pub fn parse(content: &str) -> Self {
+        Self {
+            lines: content.lines().map(|l| s!(l)).collect(),
+        }
+    }
+
+    /**
+    Read an `authorized_keys` file.
+
+    # Parameters
+    * `path`: The path of the file.
+
+    # Returns
+    The content of the file, empty when the file doesn't exist.
+    The file belongs to the user, so an error is returned when it is a link or not a regular file.
+     */
+    pub fn read(path: &Path) -> io::Result<Self> {
+        // The file is checked through the descriptor, so it can't be replaced between the check and the read.
+        match open_no_follow(path, libc::O_NONBLOCK) {
+            Ok(file) => Self::read_file(file, path),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e),
+        }
+    }
+
+    /**
+    Read and parse an opened `authorized_keys` file.
+
+    # Parameters
+    * `file`: The opened file.
+    * `path`: The path of the file, used in the error messages.
+
+    # Returns
+    The parsed content, or an error when the file is not a regular file.
+     */
+    fn read_file(mut file: File, path: &Path) -> io::Result<Self> {
+        if !file.metadata()?.is_file() {
+            return Err(operation_error(format!(
+                "{} is not a regular file",
+                path.display()
+            )));
+        }
+
+        let mut content = String::new();
+        file.read_to_string(&mut content)?;
+        Ok(Self::parse(&content))
+    }
+
+    /**
+    Get the keys of the file, inside or outside of the managed block.
+
+    # Returns
+    Every key line, without the comments and empty lines.
+     */
+    pub fn keys(&self) -> Vec<String> {
+        self.lines.iter().filter(|l| is_key(l)).cloned().collect()
+    }
+
+    /**
+    Get the keys of the managed block.
+
+    # Returns
+    The key lines between the markers, `None` when the file doesn't have a managed block.
+     */
+    pub fn managed_keys(&self) -> Option<Vec<String>> {
+        let (begin, end) = self.block()?;
+        Some(
+            self.lines[begin + 1..end]
+                .iter()
+                .filter(|l| is_key(l))
+                .cloned()
+                .collect(),
+        )
+    }
+
+    /**
+    Get the current keys handled by a mode.
+
+    # Parameters
+    * `mode`: The way the file is managed.
+
+    # Returns
+    Every key of the file, or the keys of the managed block.
+     */
+    pub fn current_keys(&self, mode: AuthorizedKeysMode) -> Vec<String> {
+        match mode {
+            AuthorizedKeysMode::Exclusive => self.keys(),
+            AuthorizedKeysMode::Block => self.managed_keys().unwrap_or_default(),
+        }
+    }
+
+    /**
+    Render the file with a new list of keys.
+
+    # Parameters
+    * `keys`: The keys, as lines of the file.
+    * `mode`: `Exclusive` to replace the whole file,
+      `Block` to only replace the managed block and keep the other lines.
+
+    # Returns
+    The new content of the file.
+     */
+    pub fn render(&self, keys: &[String], mode: AuthorizedKeysMode) -> String {
+        let mut lines = Vec::<&str>::new();
+        match mode {
+            AuthorizedKeysMode::Exclusive => {
+                lines.push(MANAGED_HEADER);
+                lines.extend(keys.iter().map(|k| k.as_str()));
+            }
+            AuthorizedKeysMode::Block => {
+                let (before, after) = match self.block() {
+                    Some((begin, end)) => (&self.lines[..begin], &self.lines[end + 1..]),
+                    None => (&self.lines[..], &[] as &[String]),
+                };
+                lines.extend(before.iter().map(|l| l.as_str()));
+                // No block is left when there's no key to manage.
+                if !keys.is_empty() {
+                    lines.push(BEGIN_MARKER);
+                    lines.extend(keys.iter().map(|k| k.as_str()));
+                    lines.push(END_MARKER);
+                }
+                lines.extend(after.iter().map(|l| l.as_str()));
+            }
+        }
+
+        let mut content = lines.join("\n");
+        if !content.is_empty() {
+            content.push('\n');
+        }
+        content
+    }
+
+    /**
+    Find the managed block.
+
+    # Returns
+    The index of the begin and end markers, `None` when the block is missing or not closed.
+     */
+    fn block(&self) -> Option<(usize, usize)> {
+        let begin = self.lines.iter().position(|l| l.trim() == BEGIN_MARKER)?;
+        let end = self.lines[begin..]
+            .iter()
+            .position(|l| l.trim() == END_MARKER)?;
+        Some((begin, begin + end))
+    }
+}
+
+/**
+Get the path of the `authorized_keys` file of a user.
+
+# Parameters
+* `root`: The root of the filesystem containing the home directory.
+* `home`: The home directory of the user.
+ */
+pub fn authorized_keys_path(root: &Path, home: &str) -> PathBuf {
+    system_parser::system_path(root, home).join(consts::AUTHORIZED_KEYS_FILE)
+}
+
+/**
+Write the `authorized_keys` file of a user.
+The `.ssh` directory is created when missing, the file is replaced with an atomic rename,
+both are owned by the user and only accessible by them (`0700` and `0600`).
+The files are opened, replaced and owned through the descriptor of the `.ssh` directory,
+so the user can't redirect them with a symbolic link.
+
+# Parameters
+* `root`: The root of the filesystem containing the user database and the home directory.
+* `username`: The name of the user, looked up in the user file.
+* `keys`: The keys, as lines of the file.
+* `mode`: The way the file is managed.
+ */
+pub fn write_authorized_keys(
+    root: &Path,
+    username: &str,
+    keys: &[String],
+    mode: AuthorizedKeysMode,
+) -> io::Result<()> {
+    let user_path = system_parser::system_path(root, consts::USER_FILE);
+    let entry = system_parser::parse_system_file(&user_path, consts::USER_FIELDS)?
+        .into_iter()
+        .find(|e| e.get(consts::NAME_FIELD).map(|n| n.as_str()) == Some(username))
+        .ok_or_else(|| operation_error(format!("user '{}' does not exist", username)))?;
+    let field = |name: &str| entry.get(name).cloned().unwrap_or_default();
+    let id = |name: &str| {
+        field(name)
+            .parse::<u32>()
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    };
+    let (uid, gid) = (id(consts::UID_FIELD)?, id(consts::GID_FIELD)?);
+
+    let path = authorized_keys_path(root, &field(consts::HOME_FIELD));
+    let ssh_dir = path.parent().unwrap_or(&path);
+    // The home directory belongs to the user, never follow a link they could have made:
+    // the files are only reached through the descriptor of the `.ssh` directory.
+    match fs::DirBuilder::new().mode(0o700).create(ssh_dir) {
+        Err(e) if e.kind() != io::ErrorKind::AlreadyExists => return Err(e),
+        _ => {}
+    }
+    let dir = match open_no_follow(ssh_dir, libc::O_DIRECTORY) {
+        // `O_NOFOLLOW` with `O_DIRECTORY` also fails with `ENOTDIR` on a link.
+        Err(e) if e.raw_os_error() == Some(libc::ENOTDIR) => {
+            let link = fs::symlink_metadata(ssh_dir).is_ok_and(|m| m.file_type().is_symlink());
+            return Err(operation_error(format!(
+                "{} is {}",
+                ssh_dir.display(),
+                match link {
+                    true => "a symbolic link",
+                    false => "not a directory",
+                }
+            )));
+        }
+        rst => rst?,
+    };
+    unix_fs::fchown(&dir, Some(uid), Some(gid))?;
+    dir.set_permissions(fs::Permissions::from_mode(0o700))?;
+
+    let current = match open_at(&dir, &path, libc::O_RDONLY | libc::O_NONBLOCK, 0) {
+        Ok(file) => AuthorizedKeys::read_file(file, &path)?,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => AuthorizedKeys::default(),
+        Err(e) => return Err(e),
+    };
+    let content = current.render(keys, mode);
+
+    let tmp_path = path.with_file_name(format!(
+        "{}+",
+        path.file_name().unwrap_or_default().to_string_lossy()
+    ));
+    let (name, tmp_name) = (file_name(&path)?, file_name(&tmp_path)?);
+    // SAFETY: The directory descriptor is open as long as `dir`,
+    // the name is a valid NUL-terminated string living until the end of the call.
+    if unsafe { libc::unlinkat(dir.as_raw_fd(), tmp_name.as_ptr(), 0) } < 0 {
+        let e = io::Error::last_os_error();
+        if e.kind() != io::ErrorKind::NotFound {
+            return Err(e);
+        }
+    }
+    // The temporary file is created exclusively and owned through its descriptor,
+    // a link made in its place fails instead of being followed.
+    let mut file = open_at(
+        &dir,
+        &tmp_path,
+        libc::O_WRONLY | libc::O_CREAT | libc::O_EXCL,
+        0o600,
+    )?;
+    file.write_all(content.as_bytes())?;
+    file.sync_all()?;
+    unix_fs::fchown(&file, Some(uid), Some(gid))?;
+
+    // SAFETY: The directory descriptor is open as long as `dir`,
+    // both names are valid NUL-terminated strings living until the end of the call.
+    let renamed = unsafe {
+        libc::renameat(
+            dir.as_raw_fd(),
+            tmp_name.as_ptr(),
+            dir.as_raw_fd(),
+            name.as_ptr(),
+        )
+    };
+    match renamed < 0 {
+        true => Err(io::Error::last_os_error()),
+        false => Ok(()),
+    }
+}
+
+/**
+Open a file of a directory of a user, without following a link they could have made.
+
+# Parameters
+* `dir`: The opened directory containing the file.
+* `path`: The path of the file, only its name is opened relative to `dir`.
+* `flags`: The flags of `openat` (e.g. `O_RDONLY`).
+* `mode`: The permissions of a created file.
+
+# Returns
+The opened file, or an error when the file is a symbolic link.
+ */
+fn open_at(dir: &File, path: &Path, flags: i32, mode: libc::mode_t) -> io::Result<File> {
+    let name = file_name(path)?;
+    let flags = flags | libc::O_NOFOLLOW | libc::O_CLOEXEC;
+    // SAFETY: The directory descriptor is open as long as `dir`,
+    // the name is a valid NUL-terminated string living until the end of the call.
+    let fd = unsafe { libc::openat(dir.as_raw_fd(), name.as_ptr(), flags, mode as libc::c_uint) };
+    if fd < 0 {
+        let e = io::Error::last_os_error();
+        return match e.raw_os_error() == Some(libc::ELOOP) {
+            true => Err(operation_error(format!(
+                "{} is a symbolic link",
+                path.display()
+            ))),
+            false => Err(e),
+        };
+    }
+    // SAFETY: The descriptor was just opened and isn't owned by anything else.
+    Ok(unsafe { File::from_raw_fd(fd) })
+}
+
+/**
+Get the name of a file as a C string, for the system calls relative to a directory.
+
+# Parameters
+* `path`: The path of the file.
+ */
+fn file_name(path: &Path) -> io::Result<CString> {
+    let name = path
+        .file_name()
+        .ok_or_else(|| operation_error(format!("{} has no file name", path.display())))?;
+    CString::new(name.as_bytes()).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+}
+
+/**
+Open a file of a user for reading, without following a link they could have made.
+
+# Parameters
+* `path`: The path of the file.
+* `flags`: The additional flags of `open` (e.g. `O_DIRECTORY`).
+
+# Returns
+The opened file, or an error when the path is a symbolic link.
+ */
+fn open_no_follow(path: &Path, flags: i32) -> io::Result<File> {
+    let rst = OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_NOFOLLOW | flags)
+        .open(path);
+    match rst {
+        Err(e) if e.raw_os_error() == Some(libc::ELOOP) => Err(operation_error(format!(
+            "{} is a symbolic link",
+            path.display()
+        ))),
+        rst => rst,
+    }
+}
+
+/**
+Check if a line of an `authorized_keys` file is a key.
+
+# Parameters
+* `line`: The line to check.
+ */
+fn is_key(line: &str) -> bool {
+    let line = line.trim();
+    !line.is_empty() && !line.starts_with('#')
+}
@@ -0,0 +1,157 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs, io,
+    path::Path,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    consts,
+    differ::Differ,
+    error::Summary,
+    sync_structs::{HomePolicy, LocalRoot},
+    system_parser::{self, SystemGroup, SystemUser},
+};
+
+/**
+The users and groups managed by the application.
+
+Only the managed users and groups can be removed from the system,
+the accounts created by hand are left untouched.
+ */
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct ManagedState {
+    pub users: BTreeSet<String>,
+    pub groups: BTreeSet<String>,
+    /// The home policy of the users overriding the one of the sync file,
+    /// kept so it still applies once the user is removed from the sync file.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub home_policies: BTreeMap<String, HomePolicy>,
+}
+
+impl ManagedState {
+    /**
+    Read the managed state file.
+
+    # Parameters
+    * `root`: The root of the filesystem containing the state file.
+
+    # Returns
+    The managed state, empty when the file doesn't exist yet.
+     */
+    pub fn load(root: &Path) -> io::Result<Self> {
+        let path = system_parser::system_path(root, consts::STATE_FILE);
+        match fs::read_to_string(&path) {
+            Ok(content) => serde_json::from_str(&content)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e),
+        }
+    }
+
+    /**
+    Write the managed state file.
+    The file is written next to its final location then renamed so it's never partially written.
+
+    # Parameters
+    * `root`: The root of the filesystem containing the state file.
+     */
+    pub fn save(&self, root: &Path) -> io::Result<()> {
+        let path = system_parser::system_path(root, consts::STATE_FILE);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        let content = serde_json::to_string_pretty(self)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        let tmp_path = path.with_extension("json.tmp");
+        fs::write(&tmp_path, content)?;
+        fs::rename(&tmp_path, &path)
+    }
+
+    /**
+    Compute the managed state after a sync.
+    The users and groups added by the sync are managed along with the previously managed ones,
+    as long as they exist on the system.
+    The existing accounts of the sync file, e.g. created by hand, are only managed when adopted.
+
+    # Parameters
+    * `sync`: A reference to the `LocalRoot` of the sync file.
+    * `diff`: The changes applied by the sync.
+    * `summary`: The result of the operations, only the users and groups successfully added are recorded.
+    * `adopt`: Also manage the users and groups of the sync file which already existed.
+    * `system`: The tuple with the list of system users (0) and system groups (1) after the sync.
+
+    # Returns
+    The new managed state.
+     */
+    pub fn after_sync(
+        &self,
+        sync: &LocalRoot,
+        diff: &Differ,
+        summary: &Summary,
+        adopt: bool,
+        system: &(Vec<SystemUser>, Vec<SystemGroup>),
+    ) -> Self {
+        let (system_users, system_groups) = system;
+        let (add_users, add_groups) = &diff.add;
+
+        // The operations are described like the runner records them.
+        let added_users = add_users
+            .iter()
+            .map(|u| &u.username)
+            .filter(|name| summary.applied(&format!("add user {}", name)));
+        let adopted_users = sync.users.iter().map(|u| &u.username).filter(|_| adopt);
+        let users: BTreeSet<String> = added_users
+            .chain(adopted_users)
+            .chain(self.users.iter())
+            .filter(|name| system_users.iter().any(|u| &u.username == *name))
+            .cloned()
+            .collect();
+
+        let added_groups = add_groups
+            .iter()
+            .map(|g| &g.name)
+            .filter(|name| summary.applied(&format!("add group {}", name)));
+        let adopted_groups = sync.groups.iter().map(|g| &g.name).filter(|_| adopt);
+        let groups = added_groups
+            .chain(adopted_groups)
+            .chain(self.groups.iter())
+            .filter(|name| system_groups.iter().any(|g| &g.name == *name))
+            .cloned()
+            .collect();
+
+        let mut home_policies = self.home_policies.clone();
+        for user in &sync.users {
+            match user.home_policy == HomePolicy::default() {
+                true => home_policies.remove(&user.username),
+                false => home_policies.insert(user.username.clone(), user.home_policy.clone()),
+            };
+        }
+        home_policies.retain(|name, _| users.contains(name));
+
+        Self {
+            users,
+            groups,
+            home_policies,
+        }
+    }
+
+    /**
+    Get the home policy of a managed user.
+
+    # Parameters
+    * `sync`: A reference to the `LocalRoot` of the sync file.
+    * `username`: The name of the user.
+
+    # Returns
+    The recorded policy of the user, completed by the one of the sync file.
+     */
+    pub fn home_policy(&self, sync: &LocalRoot, username: &str) -> HomePolicy {
+        match self.home_policies.get(username) {
+            Some(policy) => policy.or(&sync.home_policy),
+            None => sync.home_policy.clone(),
+        }
+    }
+}
@@ -0,0 +1,200 @@
+use std::{
+    fs::{self, OpenOptions},
+    io::{self, Write},
+    os::unix::fs::OpenOptionsExt,
+    path::Path,
+    process::Command,
+};
+
+use crate::{backend::operation_error, consts, sync_structs::LocalRoot, system_parser};
+
+/// The first line of the drop-in.
+const HEADER: &str = "# Generated by user-sync from the sync file, local changes are overwritten.";
+
+/**
+Render the sudoers drop-in of the sync file.
+
+# Parameters
+* `sync`: A reference to the `LocalRoot` of the sync file.
+
+# Returns
+The content of the drop-in, `None` when there's no rule.
+ */
+pub fn render(sync: &LocalRoot) -> Option<String> {
+    let users = sync
+        .users
+        .iter()
+        .flat_map(|u| u.sudo.iter().map(move |rule| (u.username.clone(), rule)));
+    let groups = sync.groups.iter().flat_map(|g| {
+        g.sudo
+            .iter()
+            .map(move |rule| (format!("%{}", g.name), rule))
+    });
+
+    let rules: Vec<String> = users
+        .chain(groups)
+        .map(|(who, rule)| format!("{} {}", who, rule.trim()))
+        .collect();
+    if rules.is_empty() {
+        return None;
+    }
+
+    Some(format!("{}\n{}\n", HEADER, rules.join("\n")))
+}
+
+/**
+Read the sudoers drop-in.
+
+# Parameters
+* `root`: The root of the filesystem containing the drop-in.
+
+# Returns
+The content of the drop-in, `None` when it doesn't exist.
+ */
+pub fn read(root: &Path) -> io::Result<Option<String>> {
+    let path = system_parser::system_path(root, consts::SUDOERS_FILE);
+    match fs::read_to_string(path) {
+        Ok(content) => Ok(Some(content)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/**
+Install the sudoers drop-in, or remove it.
+The drop-in is written next to its final location with a name ignored by sudo,
+checked with `visudo -c` when sudo is installed, then renamed so sudo never reads an invalid file.
+
+# Parameters
+* `root`: The root of the filesystem containing the drop-in.
+* `content`: The content of the drop-in, `None` to remove it.
+ */
+pub fn install(root: &Path, content: Option<&str>) -> io::Result<()> {
+    let path = system_parser::system_path(root, consts::SUDOERS_FILE);
+    let content = match content {
+        Some(content) => content,
+        None => {
+            return match fs::remove_file(&path) {
+                Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+                rst => rst,
+            }
+        }
+    };
+
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    // sudo skips the files of `sudoers.d` containing a `.`.
+    let tmp_path = path.with_extension("tmp");
+    match fs::remove_file(&tmp_path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+        _ => {}
+    }
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o440)
+        .open(&tmp_path)?;
+    file.write_all(content.as_bytes())?;
+    file.sync_all()?;
+
+    if let Err(e) = check(&tmp_path) {
+        fs::remove_file(&tmp_path).ok();
+        return Err(e);
+    }
+    fs::rename(&tmp_path, &path)
+}
+
+/**
+Check the syntax of a sudoers file with `visudo -c`.
+The rules are also checked by the validation, so the check is skipped when sudo isn't installed.
+
+# Parameters
+* `path`: The file to check.
+ */
+fn check(path: &Path) -> io::Result<()> {
+    if !Path::new(consts::CHECK_SUDOERS_CMD).exists() {
+        return Ok(());
+    }
+
+    let output = Command::new(consts::CHECK_SUDOERS_CMD)
+        .arg("-c")
+        .arg("-q")
+        .arg("-f")
+        .arg(path)
+        .output()?;
+    match output.status.success() {
+        true => Ok(()),
+        false => Err(operation_error(format!(
+            "{} -c -q -f {} ({})\n{}{}",
+            consts::CHECK_SUDOERS_CMD,
+            path.display(),
+            output.status,
+            String::from_utf8_lossy(&output.stdout),
+            String::from_utf8_lossy(&output.stderr).trim_end()
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{os::unix::fs::PermissionsExt, process};
+
+    use super::*;
+
+    #[test]
+    fn rules_of_users_and_groups_are_rendered() {
+        let mut sync: LocalRoot = serde_json::from_str(
+            r#"{
+                "encrypted": true,
+                "users": [
+                    { "username": "alice", "password": "!", "groups": ["ops"], "sudo": [" ALL=(ALL) ALL "] },
+                    { "username": "bob", "password": "!", "groups": ["ops"] }
+                ],
+                "groups": [
+                    { "name": "ops", "sudo": ["ALL=(root) NOPASSWD: /usr/bin/systemctl", "web01=ALL"] }
+                ]
+            }"#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            render(&sync).unwrap(),
+            format!(
+                "{}\nalice ALL=(ALL) ALL\n%ops ALL=(root) NOPASSWD: /usr/bin/systemctl\n%ops web01=ALL\n",
+                HEADER
+            )
+        );
+
+        // The drop-in is removed once there's no rule left.
+        sync.users[0].sudo.clear();
+        sync.groups[0].sudo.clear();
+        assert_eq!(render(&sync), None);
+    }
+
+    #[test]
+    fn drop_in_is_installed_and_removed() {
+        let root = std::env::temp_dir().join(format!("user-sync-sudoers-{}", process::id()));
+        let _ = fs::remove_dir_all(&root);
+        assert_eq!(read(&root).unwrap(), None);
+        // Removing a missing drop-in is a success.
+        install(&root, None).unwrap();
+
+        let content = format!("{}\nalice ALL=(ALL) ALL\n", HEADER);
+        install(&root, Some(&content)).unwrap();
+        assert_eq!(read(&root).unwrap(), Some(content.clone()));
+        let path = system_parser::system_path(&root, consts::SUDOERS_FILE);
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o440);
+        // The temporary file is renamed over the drop-in.
+        assert!(!path.with_extension("tmp").exists());
+
+        // A read-only drop-in is still replaced.
+        install(&root, Some(HEADER)).unwrap();
+        assert_eq!(read(&root).unwrap().as_deref(), Some(HEADER));
+
+        install(&root, None).unwrap();
+        assert_eq!(read(&root).unwrap(), None);
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -1,21 +1,241 @@
-use serde::Deserialize;
+use std::{
+    fmt,
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{consts, date, s, system_parser::AgingField};
+
+/**
+The format of a sync file.
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    Json,
+    Yaml,
+    Toml,
+}
+
+impl Format {
+    /**
+    Get a format from its name.
+
+    # Parameters
+    * `name`: The name of the format (`json`, `yaml`, `yml` or `toml`).
+
+    # Returns
+    The format, `None` when the name is unknown.
+     */
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "json" => Some(Self::Json),
+            "yaml" | "yml" => Some(Self::Yaml),
+            "toml" => Some(Self::Toml),
+            _ => None,
+        }
+    }
+
+    /**
+    Guess the format of a file from its extension.
+
+    # Parameters
+    * `path`: The path of the file.
+
+    # Returns
+    The format of the file, defaults to JSON when the extension is unknown.
+     */
+    pub fn from_path(path: &Path) -> Self {
+        path.extension()
+            .and_then(|ext| Self::from_name(&ext.to_string_lossy()))
+            .unwrap_or(Self::Json)
+    }
+}
 
 #[derive(Debug, Deserialize)]
 pub struct Root {
     pub local: Option<LocalRoot>,
-    ldap: Option<LDAP>,
+    pub filter: Option<Filter>,
+    pub ldap: Option<Ldap>,
+    /// The source winning when a user or group is defined by both sources.
+    #[serde(default)]
+    pub precedence: Source,
+    /// How the home directories are created and removed, for every user.
+    #[serde(default)]
+    pub home_policy: HomePolicy,
 }
 
-#[derive(Debug, Deserialize)]
+/**
+The source of a user or group.
+ */
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Source {
+    #[default]
+    Local,
+    Ldap,
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Local => write!(f, "local"),
+            Self::Ldap => write!(f, "ldap"),
+        }
+    }
+}
+
+impl Root {
+    /**
+    Parse the content of a sync file.
+
+    # Parameters
+    * `content`: The content of the file.
+    * `format`: The format of the content.
+
+    # Returns
+    The parsed sync file, or a description of the parse error.
+     */
+    pub fn parse(content: &str, format: Format) -> Result<Self, String> {
+        match format {
+            Format::Json => serde_json::from_str(content).map_err(|e| e.to_string()),
+            Format::Yaml => serde_yaml::from_str(content).map_err(|e| e.to_string()),
+            Format::Toml => toml::from_str(content).map_err(|e| e.to_string()),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
 pub struct LocalRoot {
     pub encrypted: bool,
     pub users: Vec<User>,
     pub groups: Vec<Group>,
+    /// The home policy of the sync file, set once the sources are merged.
+    #[serde(skip)]
+    pub home_policy: HomePolicy,
+}
+
+impl LocalRoot {
+    /**
+    Check if the password of a user is encrypted.
+
+    # Parameters
+    * `user`: The user.
+
+    # Returns
+    The `encrypted` flag of the user, or the one of the root when the user doesn't have one.
+     */
+    pub fn is_encrypted(&self, user: &User) -> bool {
+        user.encrypted.unwrap_or(self.encrypted)
+    }
+
+    /**
+    Get the home policy of a user.
+
+    # Parameters
+    * `user`: The user.
+
+    # Returns
+    The policy of the user, completed by the one of the sync file.
+     */
+    pub fn user_home_policy(&self, user: &User) -> HomePolicy {
+        user.home_policy.or(&self.home_policy)
+    }
+
+    /**
+    Merge the members declared by the groups into the groups of the users.
+
+    A user is a member of a group when either the user or the group declares it,
+    the synchronized users are then removed from the `members` of the groups
+    so only the other accounts (e.g. `www-data`) are left to the group.
+     */
+    pub fn resolve_members(&mut self) {
+        let users = &mut self.users;
+        for group in &mut self.groups {
+            let name = &group.name;
+            if let Some(members) = &mut group.members {
+                let mut others = Vec::<String>::new();
+                for member in members.drain(..) {
+                    match users.iter_mut().find(|u| u.username == member) {
+                        Some(user) if !user.groups.contains(name) => user.groups.push(name.clone()),
+                        Some(_) => {}
+                        None if !others.contains(&member) => others.push(member),
+                        None => {}
+                    }
+                }
+                *members = others;
+            }
+        }
+    }
+
+    /**
+    Check if a user is synchronized.
+
+    # Parameters
+    * `username`: The name of the user.
+     */
+    pub fn has_user(&self, username: &str) -> bool {
+        self.users.iter().any(|u| u.username == username)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Ldap {
+    /// The URL of the server (`ldap://` or `ldaps://`).
+    pub url: String,
+    /// Upgrade an `ldap://` connection with StartTLS.
+    #[serde(default)]
+    pub starttls: bool,
+    /// The DN to bind with, anonymous bind when missing.
+    pub bind_dn: Option<String>,
+    /// The password of the bind DN.
+    pub bind_secret: Option<String>,
+    /// A file containing the password of the bind DN, used when `bind_secret` is missing.
+    pub bind_secret_file: Option<PathBuf>,
+    pub users: LdapSearch,
+    pub groups: LdapSearch,
+    #[serde(default)]
+    pub attributes: LdapAttributes,
 }
 
 #[derive(Debug, Deserialize)]
-pub struct LDAP {
-    // Unsupported at the moment.
+pub struct LdapSearch {
+    /// The base DNs where the entries are searched.
+    pub base_dns: Vec<String>,
+    /// The search filter, defaults to the `posixAccount` or `posixGroup` object class.
+    pub filter: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct LdapAttributes {
+    pub uid: String,
+    pub uid_number: String,
+    pub gid_number: String,
+    pub user_password: String,
+    pub group_name: String,
+    pub member_uid: String,
+    pub member: String,
+    pub gecos: String,
+    pub home: String,
+    pub shell: String,
+}
+
+impl Default for LdapAttributes {
+    fn default() -> Self {
+        Self {
+            uid: s!("uid"),
+            uid_number: s!("uidNumber"),
+            gid_number: s!("gidNumber"),
+            user_password: s!("userPassword"),
+            group_name: s!("cn"),
+            member_uid: s!("memberUid"),
+            member: s!("member"),
+            gecos: s!("gecos"),
+            home: s!("homeDirectory"),
+            shell: s!("loginShell"),
+        }
+    }
 }
 
 #[derive(Debug, Deserialize, Clone)]
@@ -23,11 +243,312 @@ pub struct User {
     pub username: String,
     pub uid: Option<u32>,
     pub password: String,
+    /// Overrides the `encrypted` flag of the root for this user.
+    pub encrypted: Option<bool>,
     pub groups: Vec<String>,
+    /// The comment (full name) of the user.
+    pub gecos: Option<String>,
+    /// The home directory of the user.
+    pub home: Option<String>,
+    /// The login shell of the user.
+    pub shell: Option<String>,
+    /// The password aging and account expiry of the user.
+    #[serde(default)]
+    pub aging: Aging,
+    /// Whether the user can log in.
+    #[serde(default)]
+    pub state: AccountState,
+    /// Overrides the home policy of the sync file for this user.
+    #[serde(default)]
+    pub home_policy: HomePolicy,
+    /// The SSH keys of the user, only managed when set.
+    pub ssh_keys: Option<Vec<SshKey>>,
+    /// How the `authorized_keys` file of the user is managed.
+    #[serde(default)]
+    pub ssh_keys_mode: AuthorizedKeysMode,
+    /// The sudo rules of the user (e.g. `ALL=(ALL:ALL) ALL`).
+    #[serde(default)]
+    pub sudo: Vec<String>,
+    #[serde(skip)]
+    pub source: Source,
+}
+
+/**
+An SSH public key, either the key alone (`ssh-ed25519 AAAA... comment`) or with its options.
+ */
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+#[serde(untagged)]
+pub enum SshKey {
+    Key(String),
+    WithOptions {
+        key: String,
+        /// The options of the key (e.g. `from="10.0.0.0/8"` or `no-port-forwarding`).
+        #[serde(default)]
+        options: Vec<String>,
+    },
+}
+
+impl SshKey {
+    /**
+    Get the key as a line of an `authorized_keys` file.
+     */
+    pub fn line(&self) -> String {
+        match self {
+            Self::Key(key) => s!(key.trim()),
+            Self::WithOptions { key, options } if options.is_empty() => s!(key.trim()),
+            Self::WithOptions { key, options } => format!("{} {}", options.join(","), key.trim()),
+        }
+    }
+
+    /**
+    Get the key, without its options.
+     */
+    pub fn key(&self) -> &str {
+        match self {
+            Self::Key(key) | Self::WithOptions { key, .. } => key,
+        }
+    }
+}
+
+/**
+How the `authorized_keys` file of a user is managed.
+ */
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum AuthorizedKeysMode {
+    /// The file only contains the keys of the sync file.
+    #[default]
+    Exclusive,
+    /// The keys of the sync file are kept in a block, the keys added by the user are preserved.
+    Block,
+}
+
+/**
+The password aging of a user, only the fields set are managed.
+The periods are in days, `-1` disables the feature like an empty field of the shadow file.
+ */
+#[derive(Debug, Deserialize, Clone, Default, PartialEq)]
+pub struct Aging {
+    /// Minimum number of days between password changes.
+    pub minimum: Option<i64>,
+    /// Maximum number of days a password is valid.
+    pub maximum: Option<i64>,
+    /// Number of days of warning before the password expires.
+    pub warn: Option<i64>,
+    /// Number of days after the password expires before the account is disabled.
+    pub inactive: Option<i64>,
+    /// The expiry date of the account (`YYYY-MM-DD`), `never` to disable.
+    pub expire: Option<String>,
+}
+
+impl Aging {
+    /**
+    Get the managed fields with their value in the shadow file unit.
+
+    # Returns
+    Every field set, with the days (or days since 1970-01-01 for the expiry date),
+    `None` when the feature is disabled.
+     */
+    pub fn fields(&self) -> Vec<(AgingField, Option<i64>)> {
+        let days = |value: i64| Some(value).filter(|v| *v >= 0);
+        let mut fields = Vec::<(AgingField, Option<i64>)>::new();
+        for (field, value) in [
+            (AgingField::Minimum, self.minimum),
+            (AgingField::Maximum, self.maximum),
+            (AgingField::Warn, self.warn),
+            (AgingField::Inactive, self.inactive),
+        ] {
+            if let Some(value) = value {
+                fields.push((field, days(value)));
+            }
+        }
+        if self.expire.is_some() {
+            fields.push((AgingField::Expire, self.expire_days()));
+        }
+
+        fields
+    }
+
+    /**
+    Get the expiry date of the account.
+
+    # Returns
+    The days since 1970-01-01, `None` when the account never expires or the date is unset.
+     */
+    pub fn expire_days(&self) -> Option<i64> {
+        self.expire.as_deref().and_then(date::parse_date)
+    }
+}
+
+impl User {
+    /**
+    Get the managed password aging fields of the user.
+
+    # Returns
+    The fields of `Aging::fields()`, without the expiry date when the account is disabled
+    since it is then owned by the account state.
+     */
+    pub fn aging_fields(&self) -> Vec<(AgingField, Option<i64>)> {
+        let mut fields = self.aging.fields();
+        if self.state == AccountState::Disabled {
+            fields.retain(|(field, _)| *field != AgingField::Expire);
+        }
+
+        fields
+    }
+
+    /**
+    Get the SSH keys of the user as lines of an `authorized_keys` file.
+
+    # Returns
+    The lines without duplicates, `None` when the keys aren't managed.
+     */
+    pub fn ssh_key_lines(&self) -> Option<Vec<String>> {
+        let keys = self.ssh_keys.as_ref()?;
+        let mut lines = Vec::<String>::new();
+        for line in keys.iter().map(SshKey::line) {
+            if !lines.contains(&line) {
+                lines.push(line);
+            }
+        }
+        Some(lines)
+    }
+}
+
+/**
+The state of a user account.
+ */
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum AccountState {
+    /// The user can log in.
+    #[default]
+    Active,
+    /// The password is locked, other authentication methods (e.g. SSH keys) still work.
+    Locked,
+    /// The password is locked, the account is expired and the shell is `nologin`.
+    Disabled,
+}
+
+impl fmt::Display for AccountState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Active => write!(f, "active"),
+            Self::Locked => write!(f, "locked"),
+            Self::Disabled => write!(f, "disabled"),
+        }
+    }
+}
+
+/**
+How the home directories are created and removed, only the fields set are used.
+ */
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+pub struct HomePolicy {
+    /// The skeleton directory copied in a new home directory, `/etc/skel` when missing.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub skel: Option<String>,
+    /// The permissions of a new home directory, in octal (e.g. `0750`).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mode: Option<String>,
+    /// What is done with the home directory of a removed user, `keep` when missing.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub remove: Option<HomeRemoval>,
+    /// The directory of the archived home directories.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub archive_dir: Option<String>,
+}
+
+impl HomePolicy {
+    /**
+    Complete a policy with the fields of another one.
+
+    # Parameters
+    * `default`: The policy used for the fields which aren't set.
+     */
+    pub fn or(&self, default: &HomePolicy) -> HomePolicy {
+        HomePolicy {
+            skel: self.skel.clone().or_else(|| default.skel.clone()),
+            mode: self.mode.clone().or_else(|| default.mode.clone()),
+            remove: self.remove.or(default.remove),
+            archive_dir: self
+                .archive_dir
+                .clone()
+                .or_else(|| default.archive_dir.clone()),
+        }
+    }
+
+    /**
+    Parse the permissions of a new home directory.
+
+    # Returns
+    The mode, `None` when it isn't set or it is invalid.
+     */
+    pub fn mode(&self) -> Option<u32> {
+        self.mode
+            .as_ref()
+            .and_then(|mode| u32::from_str_radix(mode, 8).ok())
+            .filter(|mode| *mode <= 0o7777)
+    }
+
+    /**
+    Get the path of the archive of a removed home directory.
+
+    # Parameters
+    * `username`: The name of the removed user.
+
+    # Returns
+    The absolute path of the archive, named after the user and the current date.
+     */
+    pub fn archive_file(&self, username: &str) -> String {
+        format!(
+            "{}/{}-{}.tar.gz",
+            self.archive_dir
+                .as_deref()
+                .unwrap_or(consts::HOME_ARCHIVE_DIR)
+                .trim_end_matches('/'),
+            username,
+            date::format_date(date::today())
+        )
+    }
+}
+
+/**
+What is done with the home directory of a removed user.
+ */
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum HomeRemoval {
+    /// The home directory is left on the system.
+    #[default]
+    Keep,
+    /// The home directory is deleted with its content.
+    Delete,
+    /// The home directory is archived in a tarball, then deleted.
+    Archive,
 }
 
 #[derive(Debug, Deserialize, Clone)]
 pub struct Group {
     pub name: String,
     pub gid: Option<u32>,
+    /// The members declared by the group, the member list is only managed when set.
+    pub members: Option<Vec<String>>,
+    /// The sudo rules of the members of the group.
+    #[serde(default)]
+    pub sudo: Vec<String>,
+    #[serde(skip)]
+    pub source: Source,
+}
+
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct Filter {
+    pub min_uid: Option<u32>,
+    pub max_uid: Option<u32>,
+    pub min_gid: Option<u32>,
+    pub max_gid: Option<u32>,
+    #[serde(default)]
+    pub ignore_users: Vec<String>,
+    #[serde(default)]
+    pub ignore_groups: Vec<String>,
 }
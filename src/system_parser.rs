@@ -1,27 +1,172 @@
 use std::{
     collections::HashMap,
-    fs,
+    fmt, fs,
     io::{self, BufRead},
+    path::{Path, PathBuf},
 };
 
-use crate::{consts, s};
+use serde::Serialize;
 
-type RawEntry = HashMap<String, String>;
+use crate::{
+    consts, date,
+    error::Error,
+    s,
+    ssh::{self, AuthorizedKeys},
+    sync_structs::AccountState,
+};
+
+pub type RawEntry = HashMap<String, String>;
 
 #[derive(Debug, Clone)]
 pub struct SystemUser {
-    pub username: String, // passwd::0
-    pub hash: String,     // shadow::1
-    pub uid: u32,         // passwd::2
-    pub gid: u32,         // passwd::3
+    pub username: String,   // passwd::0
+    pub hash: String,       // shadow::1
+    pub uid: u32,           // passwd::2
+    pub gid: u32,           // passwd::3
+    pub gecos: String,      // passwd::4
+    pub home: String,       // passwd::5
+    pub shell: String,      // passwd::6
+    pub aging: ShadowAging, // shadow::3..7
+    /// The `authorized_keys` file in the home directory.
+    pub authorized_keys: AuthorizedKeys,
+}
+
+impl SystemUser {
+    /**
+    Check if the password of the user is locked (`!` prefix of the hash).
+     */
+    pub fn is_locked(&self) -> bool {
+        self.hash.starts_with('!')
+    }
+
+    /**
+    Get the state of the account.
+    Only the expiry date written when an account is disabled (`consts::DISABLED_EXPIRE`) disables it,
+    a locked account with any other past expiry date is still `Locked`.
+
+    # Returns
+    `Disabled` when the password is locked and the account expired by the application,
+    `Locked` when only the password is locked, `Active` otherwise.
+     */
+    pub fn state(&self) -> AccountState {
+        let disabled = self.aging.expire == Some(consts::DISABLED_EXPIRE);
+        match (self.is_locked(), disabled) {
+            (true, true) => AccountState::Disabled,
+            (true, false) => AccountState::Locked,
+            _ => AccountState::Active,
+        }
+    }
+}
+
+/**
+The password aging fields of a user, in days (the expiry date is in days since 1970-01-01).
+`None` when the field is empty, which disables the feature.
+ */
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ShadowAging {
+    pub minimum: Option<i64>,
+    pub maximum: Option<i64>,
+    pub warn: Option<i64>,
+    pub inactive: Option<i64>,
+    pub expire: Option<i64>,
+}
+
+/**
+A password aging field of the shadow file.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AgingField {
+    Minimum,
+    Maximum,
+    Warn,
+    Inactive,
+    Expire,
+}
+
+impl ShadowAging {
+    /**
+    Get the value of a field.
+
+    # Parameters
+    * `field`: The field.
+     */
+    pub fn get(&self, field: AgingField) -> Option<i64> {
+        match field {
+            AgingField::Minimum => self.minimum,
+            AgingField::Maximum => self.maximum,
+            AgingField::Warn => self.warn,
+            AgingField::Inactive => self.inactive,
+            AgingField::Expire => self.expire,
+        }
+    }
+
+    /**
+    Set the value of a field.
+
+    # Parameters
+    * `field`: The field.
+    * `value`: The new value, `None` to disable the feature.
+     */
+    pub fn set(&mut self, field: AgingField, value: Option<i64>) {
+        match field {
+            AgingField::Minimum => self.minimum = value,
+            AgingField::Maximum => self.maximum = value,
+            AgingField::Warn => self.warn = value,
+            AgingField::Inactive => self.inactive = value,
+            AgingField::Expire => self.expire = value,
+        }
+    }
+}
+
+impl AgingField {
+    /**
+    Format a value of the field for display, dates are in ISO 8601.
+
+    # Parameters
+    * `value`: The value of the field, `None` when disabled.
+     */
+    pub fn format_value(&self, value: Option<i64>) -> String {
+        match (self, value) {
+            (Self::Expire, Some(days)) => date::format_date(days),
+            (Self::Expire, None) => s!("never"),
+            (_, Some(days)) => days.to_string(),
+            (_, None) => s!("none"),
+        }
+    }
+}
+
+impl fmt::Display for AgingField {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Minimum => write!(f, "minimum password age"),
+            Self::Maximum => write!(f, "maximum password age"),
+            Self::Warn => write!(f, "password warning period"),
+            Self::Inactive => write!(f, "password inactivity period"),
+            Self::Expire => write!(f, "account expiry"),
+        }
+    }
 }
 
 impl SystemUser {
-    pub fn parse_file() -> io::Result<Vec<Self>> {
+    /**
+    Parse the system users from the user and password files.
+
+    # Parameters
+    * `root`: The root of the filesystem containing the files (`/` for the live system).
+
+    # Returns
+    Every user, the ones handled by the application are selected by the `Differ`.
+     */
+    pub fn parse_file(root: &Path) -> Result<Vec<Self>, Error> {
         let nobody_username = s!("nobody");
 
-        let raw_users = filter_users(parse_system_file(consts::USER_FILE, consts::USER_FIELDS)?);
-        let raw_passwords = parse_system_file(consts::PASSWORD_FILE, consts::PASSWORD_FIELDS)?;
+        let user_path = system_path(root, consts::USER_FILE);
+        let password_path = system_path(root, consts::PASSWORD_FILE);
+        let raw_users = parse_system_file(&user_path, consts::USER_FIELDS)
+            .map_err(|e| Error::SystemUserRead(user_path.clone(), e))?;
+        let raw_passwords = parse_system_file(&password_path, consts::PASSWORD_FIELDS)
+            .map_err(|e| Error::SystemUserRead(password_path.clone(), e))?;
 
         let mut rst = Vec::<Self>::new();
         for user_entry in raw_users {
@@ -34,21 +179,23 @@ impl SystemUser {
             });
 
             if let Some(password_entry) = password_entry_opt {
+                // An unreadable file is reported when it is written.
+                let home = user_entry.get(consts::HOME_FIELD).unwrap();
+                let authorized_keys = AuthorizedKeys::read(&ssh::authorized_keys_path(root, home))
+                    .unwrap_or_default();
                 rst.push(Self {
                     username: user_entry.get(consts::NAME_FIELD).unwrap().clone(),
                     hash: password_entry.get(consts::PASSWORD_FIELD).unwrap().clone(),
-                    uid: user_entry
-                        .get(consts::UID_FIELD)
-                        .unwrap()
-                        .clone()
-                        .parse()
-                        .unwrap(),
-                    gid: user_entry
-                        .get(consts::GID_FIELD)
-                        .unwrap()
-                        .clone()
-                        .parse()
-                        .unwrap(),
+                    uid: parse_id(&user_entry, consts::UID_FIELD)
+                        .map_err(|e| Error::SystemUserRead(user_path.clone(), e))?,
+                    gid: parse_id(&user_entry, consts::GID_FIELD)
+                        .map_err(|e| Error::SystemUserRead(user_path.clone(), e))?,
+                    gecos: user_entry.get(consts::GECOS_FIELD).unwrap().clone(),
+                    home: user_entry.get(consts::HOME_FIELD).unwrap().clone(),
+                    shell: user_entry.get(consts::SHELL_FIELD).unwrap().clone(),
+                    aging: parse_aging(password_entry)
+                        .map_err(|e| Error::SystemUserRead(password_path.clone(), e))?,
+                    authorized_keys,
                 });
             }
         }
@@ -65,9 +212,19 @@ pub struct SystemGroup {
 }
 
 impl SystemGroup {
-    pub fn parse_file() -> io::Result<Vec<Self>> {
-        let raw_groups =
-            filter_groups(parse_system_file(consts::GROUP_FILE, consts::GROUP_FIELDS)?);
+    /**
+    Parse the system groups from the group file.
+
+    # Parameters
+    * `root`: The root of the filesystem containing the file (`/` for the live system).
+
+    # Returns
+    Every group, the ones handled by the application are selected by the `Differ`.
+     */
+    pub fn parse_file(root: &Path) -> Result<Vec<Self>, Error> {
+        let group_path = system_path(root, consts::GROUP_FILE);
+        let raw_groups = parse_system_file(&group_path, consts::GROUP_FIELDS)
+            .map_err(|e| Error::SystemGroupRead(group_path.clone(), e))?;
 
         let mut rst = Vec::<Self>::new();
         for group_entry in raw_groups {
@@ -75,17 +232,14 @@ impl SystemGroup {
                 .get(consts::USER_LIST_FIELD)
                 .unwrap()
                 .split(',')
+                .filter(|u| !u.is_empty())
                 .map(|u| s!(u))
                 .collect();
 
             rst.push(Self {
                 name: group_entry.get(consts::NAME_FIELD).unwrap().clone(),
-                gid: group_entry
-                    .get(consts::GID_FIELD)
-                    .unwrap()
-                    .clone()
-                    .parse()
-                    .unwrap(),
+                gid: parse_id(&group_entry, consts::GID_FIELD)
+                    .map_err(|e| Error::SystemGroupRead(group_path.clone(), e))?,
                 users,
             });
         }
@@ -94,14 +248,28 @@ impl SystemGroup {
     }
 }
 
+/**
+Resolve the path of a system file under a given root.
+
+# Parameters
+* `root`: The root of the filesystem (`/` for the live system).
+* `file`: The absolute path of the file on the live system (e.g. `consts::USER_FILE`).
+
+# Returns
+A `PathBuf` with the path of the file under the root.
+ */
+pub fn system_path(root: &Path, file: &str) -> PathBuf {
+    root.join(file.trim_start_matches('/'))
+}
+
 /**
 Parse a system file where there's an entry per line and column separated fields.
 
 # Parameters
-* `path`: A `&str` with the path to the file.
+* `path`: A `&Path` with the path to the file.
 * `fields`: À `&[&str]` with the list of all the fields to parse.
  */
-fn parse_system_file(path: &str, fields: &[&str]) -> io::Result<Vec<RawEntry>> {
+pub fn parse_system_file(path: &Path, fields: &[&str]) -> io::Result<Vec<RawEntry>> {
     // Open the file and reader.
     let file = fs::File::open(path)?;
     let reader = io::BufReader::new(file);
@@ -126,58 +294,72 @@ fn parse_system_file(path: &str, fields: &[&str]) -> io::Result<Vec<RawEntry>> {
 }
 
 /**
-Filter the list of user so only real valid users are listed.
-To be valid, a user id must be over or equal to the `consts::MIN_UID`,
-and must also not be `nobody`.
+Format an entry as a line of a system file, the reverse of `parse_system_file()`.
+
+# Parameters
+* `entry`: The entry.
+* `fields`: The list of all the fields of the file.
+
+# Returns
+The line, without the line break.
+ */
+pub fn format_system_entry(entry: &RawEntry, fields: &[&str]) -> String {
+    fields
+        .iter()
+        .map(|field| entry.get(*field).map_or("", |v| v.as_str()))
+        .collect::<Vec<&str>>()
+        .join(":")
+}
+
+/**
+Parse a numeric id field of an entry.
 
 # Parameters
-* users: The `Vec<RawEntry>` coming from `parse_system_file()` for `consts::USER_FILE`.
+* `entry`: The entry coming from `parse_system_file()`.
+* `field`: The name of the field to parse.
 
 # Returns
-A `Vec<RawEntry>` of all the valid users.
+The id, or an `InvalidData` error when the field isn't a valid id.
  */
-fn filter_users(users: Vec<RawEntry>) -> Vec<RawEntry> {
-    let nobody_username = s!("nobody");
-    users
-        .into_iter()
-        .filter(|user| {
-            let user_id = user
-                .get(consts::UID_FIELD)
-                .unwrap_or(&s!("0"))
-                .parse::<u32>()
-                .unwrap();
-            let username = user.get(consts::NAME_FIELD).unwrap_or(&nobody_username);
-
-            user_id >= consts::MIN_UID && username != &nobody_username
-        })
-        .collect()
+fn parse_id(entry: &RawEntry, field: &str) -> io::Result<u32> {
+    let value = entry.get(field).map_or("", |v| v.as_str());
+    value.parse().map_err(|_| {
+        let name = entry.get(consts::NAME_FIELD).map_or("", |n| n.as_str());
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("invalid {} `{}` for `{}`", field, value, name),
+        )
+    })
 }
 
 /**
-Filter the list of group so only real valid groups are listed.
-To be valid, a group id must be over or equal to the `consts::MIN_GID`,
-and must also not be `nogroup`.
+Parse the password aging fields of a shadow entry.
 
 # Parameters
-* groups: The `Vec<RawEntry>` coming from `parse_system_file()` for `consts::GROUP_FILE`.
+* `entry`: The entry coming from `parse_system_file()` for `consts::PASSWORD_FILE`.
 
 # Returns
-A `Vec<RawEntry>` of all the valid groups.
+The aging fields, or an `InvalidData` error when a field isn't a valid number.
  */
-fn filter_groups(groups: Vec<RawEntry>) -> Vec<RawEntry> {
-    let nogroup_name = s!("nogroup");
-
-    groups
-        .into_iter()
-        .filter(|group| {
-            let group_id = group
-                .get(consts::GID_FIELD)
-                .unwrap_or(&s!("0"))
-                .parse::<u32>()
-                .unwrap();
-            let username = group.get(consts::NAME_FIELD).unwrap_or(&nogroup_name);
-
-            group_id >= consts::MIN_GID && username != &nogroup_name
-        })
-        .collect()
+fn parse_aging(entry: &RawEntry) -> io::Result<ShadowAging> {
+    let parse_days = |field: &str| -> io::Result<Option<i64>> {
+        match entry.get(field).map_or("", |v| v.as_str()) {
+            "" => Ok(None),
+            value => value.parse().map(Some).map_err(|_| {
+                let name = entry.get(consts::NAME_FIELD).map_or("", |n| n.as_str());
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("invalid {} `{}` for `{}`", field, value, name),
+                )
+            }),
+        }
+    };
+
+    Ok(ShadowAging {
+        minimum: parse_days(consts::MINIMUM_FIELD)?,
+        maximum: parse_days(consts::MAXIMUM_FIELD)?,
+        warn: parse_days(consts::WARN_FIELD)?,
+        inactive: parse_days(consts::INACTIVE_FIELD)?,
+        expire: parse_days(consts::EXPIRE_FIELD)?,
+    })
 }
@@ -0,0 +1,641 @@
+use std::collections::HashMap;
+
+use crate::{
+    consts, date,
+    sync_structs::{HomePolicy, LocalRoot},
+};
+
+/// The maximum length of a user or group name.
+const MAX_NAME_LENGTH: usize = 32;
+
+/// The known SSH public key types.
+const SSH_KEY_TYPES: &[&str] = &[
+    "ssh-ed25519",
+    "ssh-rsa",
+    "ssh-dss",
+    "ecdsa-sha2-nistp256",
+    "ecdsa-sha2-nistp384",
+    "ecdsa-sha2-nistp521",
+    "sk-ssh-ed25519@openssh.com",
+    "sk-ecdsa-sha2-nistp256@openssh.com",
+];
+/// The known crypt hash method ids (`$<id>$...`).
+const CRYPT_IDS: &[&str] = &[
+    "1", "2a", "2b", "2x", "2y", "5", "6", "7", "y", "gy", "sha1", "md5",
+];
+
+/**
+The result of the validation of a sync file.
+ */
+#[derive(Debug, Default)]
+pub struct Validation {
+    /// The problems preventing the sync file to be applied.
+    pub errors: Vec<String>,
+    /// The suspicious definitions which are still allowed.
+    pub warnings: Vec<String>,
+}
+
+impl Validation {
+    /**
+    Check if the sync file can be applied.
+
+    # Returns
+    `true` when there's no error, warnings are allowed.
+     */
+    pub fn is_valid(&self) -> bool {
+        self.errors.is_empty()
+    }
+}
+
+/**
+Validate a sync file before anything is applied to the system.
+
+# Parameters
+* `sync`: A reference to the `LocalRoot` of the sync file.
+
+# Returns
+The errors and warnings found in the sync file.
+ */
+pub fn validate(sync: &LocalRoot) -> Validation {
+    let mut validation = Validation::default();
+
+    validate_home_policy(&sync.home_policy, "the sync file", &mut validation);
+
+    // Groups
+    for name in duplicates(sync.groups.iter().map(|g| g.name.as_str())) {
+        validation
+            .errors
+            .push(format!("Group `{}` is defined more than once.", name));
+    }
+    for group in &sync.groups {
+        if !is_valid_name(&group.name) {
+            validation
+                .errors
+                .push(format!("Group name `{}` is invalid.", group.name));
+        }
+
+        for member in group.members.iter().flatten() {
+            if !is_valid_name(member) {
+                validation.errors.push(format!(
+                    "Member `{}` of group `{}` is invalid.",
+                    member, group.name
+                ));
+            }
+        }
+        for rule in &group.sudo {
+            if !is_valid_sudo_rule(rule) {
+                validation.errors.push(format!(
+                    "Sudo rule `{}` of group `{}` is invalid.",
+                    rule, group.name
+                ));
+            }
+        }
+        for member in duplicates(group.members.iter().flatten().map(|m| m.as_str())) {
+            validation.warnings.push(format!(
+                "Member `{}` is listed more than once in group `{}`.",
+                member, group.name
+            ));
+        }
+    }
+    for gid in duplicates(sync.groups.iter().filter_map(|g| g.gid)) {
+        validation
+            .warnings
+            .push(format!("GID {} is forced on more than one group.", gid));
+    }
+
+    // Users
+    for name in duplicates(sync.users.iter().map(|u| u.username.as_str())) {
+        validation
+            .errors
+            .push(format!("User `{}` is defined more than once.", name));
+    }
+    for user in &sync.users {
+        if !is_valid_name(&user.username) {
+            validation
+                .errors
+                .push(format!("Username `{}` is invalid.", user.username));
+        }
+
+        if user.groups.is_empty() {
+            validation.errors.push(format!(
+                "User `{}` needs at least one group (its primary group).",
+                user.username
+            ));
+        }
+        for group in &user.groups {
+            if !sync.groups.iter().any(|g| &g.name == group) {
+                validation.errors.push(format!(
+                    "Group `{}` of user `{}` is not defined.",
+                    group, user.username
+                ));
+            }
+        }
+
+        if let Some(gecos) = &user.gecos {
+            if gecos.contains([':', '\n']) {
+                validation.errors.push(format!(
+                    "GECOS of user `{}` can't contain `:` or a new line.",
+                    user.username
+                ));
+            }
+        }
+        for (field, value) in [("Home directory", &user.home), ("Shell", &user.shell)] {
+            if let Some(path) = value {
+                if !path.starts_with('/') || path.contains([':', '\n']) {
+                    validation.errors.push(format!(
+                        "{} of user `{}` must be an absolute path without `:`.",
+                        field, user.username
+                    ));
+                }
+            }
+        }
+
+        let aging = &user.aging;
+        for (field, value) in [
+            ("minimum", aging.minimum),
+            ("maximum", aging.maximum),
+            ("warn", aging.warn),
+            ("inactive", aging.inactive),
+        ] {
+            if value.is_some_and(|v| v < -1) {
+                validation.errors.push(format!(
+                    "Aging `{}` of user `{}` must be a number of days or -1.",
+                    field, user.username
+                ));
+            }
+        }
+        // The earlier dates are read as another value in the shadow file:
+        // never (-1), expired on 1970-01-01 (0) or disabled by the application (1970-01-02).
+        match aging.expire.as_deref() {
+            None | Some("never") => {}
+            Some(expire) => match date::parse_date(expire) {
+                None => validation.errors.push(format!(
+                    "Expiry date `{}` of user `{}` must be `YYYY-MM-DD` or `never`.",
+                    expire, user.username
+                )),
+                Some(days) if days <= consts::DISABLED_EXPIRE => validation.errors.push(format!(
+                    "Expiry date `{}` of user `{}` must be after {}.",
+                    expire,
+                    user.username,
+                    date::format_date(consts::DISABLED_EXPIRE)
+                )),
+                Some(_) => {}
+            },
+        }
+
+        for key in user.ssh_keys.iter().flatten() {
+            if !is_valid_ssh_key(key.key()) || key.line().contains(['\n', '\r']) {
+                validation.errors.push(format!(
+                    "SSH key `{}` of user `{}` is invalid.",
+                    key.key(),
+                    user.username
+                ));
+            }
+        }
+
+        for rule in &user.sudo {
+            if !is_valid_sudo_rule(rule) {
+                validation.errors.push(format!(
+                    "Sudo rule `{}` of user `{}` is invalid.",
+                    rule, user.username
+                ));
+            }
+        }
+
+        validate_home_policy(
+            &user.home_policy,
+            &format!("user `{}`", user.username),
+            &mut validation,
+        );
+
+        if sync.is_encrypted(user) && !is_valid_hash(&user.password) {
+            validation.errors.push(format!(
+                "Password of user `{}` is not a valid crypt hash.",
+                user.username
+            ));
+        }
+    }
+    for uid in duplicates(sync.users.iter().filter_map(|u| u.uid)) {
+        validation
+            .warnings
+            .push(format!("UID {} is forced on more than one user.", uid));
+    }
+
+    validation
+}
+
+/**
+Validate a home policy.
+
+# Parameters
+* `policy`: The policy to check.
+* `owner`: The description of the definition holding the policy, for the errors.
+* `validation`: The validation receiving the errors.
+ */
+fn validate_home_policy(policy: &HomePolicy, owner: &str, validation: &mut Validation) {
+    if let Some(mode) = &policy.mode {
+        if policy.mode().is_none() {
+            validation.errors.push(format!(
+                "Home mode `{}` of {} must be an octal mode (e.g. `0750`).",
+                mode, owner
+            ));
+        }
+    }
+    for (field, value) in [
+        ("Skeleton directory", &policy.skel),
+        ("Archive directory", &policy.archive_dir),
+    ] {
+        if let Some(path) = value {
+            if !path.starts_with('/') {
+                validation
+                    .errors
+                    .push(format!("{} of {} must be an absolute path.", field, owner));
+            }
+        }
+    }
+}
+
+/**
+Find the values appearing more than once in a list.
+
+# Parameters
+* `values`: The values to check.
+
+# Returns
+Every duplicated value, once, in order of first appearance.
+ */
+fn duplicates<T: std::hash::Hash + Eq + Copy>(values: impl Iterator<Item = T>) -> Vec<T> {
+    let mut counts = HashMap::<T, usize>::new();
+    let mut order = Vec::<T>::new();
+    for value in values {
+        let count = counts.entry(value).or_insert(0);
+        *count += 1;
+        if *count == 2 {
+            order.push(value);
+        }
+    }
+
+    order
+}
+
+/**
+Check if a name is a valid POSIX user or group name.
+It must start with a lowercase letter or an underscore, followed by lowercase letters,
+digits, underscores or dashes, optionally ending with a `$` (machine accounts).
+
+# Parameters
+* `name`: The name to check.
+ */
+pub fn is_valid_name(name: &str) -> bool {
+    let name_part = name.strip_suffix('$').unwrap_or(name);
+    let mut chars = name_part.chars();
+
+    name.len() <= MAX_NAME_LENGTH
+        && chars
+            .next()
+            .is_some_and(|c| c.is_ascii_lowercase() || c == '_')
+        && chars.all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_' || c == '-')
+}
+
+/**
+Check if an SSH public key is well formed: `<type> <base64 key> [comment]`.
+
+# Parameters
+* `key`: The key, without its options.
+ */
+fn is_valid_ssh_key(key: &str) -> bool {
+    let mut parts = key.split_whitespace();
+    let key_type = parts.next().unwrap_or_default();
+    let data = parts.next().unwrap_or_default();
+
+    SSH_KEY_TYPES.contains(&key_type)
+        && !data.is_empty()
+        && data
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '+' || c == '/' || c == '=')
+}
+
+/**
+Check if a sudo rule is well formed: `<hosts>=<commands>`, without the user.
+A trailing `\` is rejected since it would continue the rule on the next line of the drop-in.
+The drop-in is also checked by `visudo` before being installed, when sudo is installed.
+
+# Parameters
+* `rule`: The rule to check (e.g. `ALL=(ALL:ALL) NOPASSWD: /usr/bin/systemctl`).
+ */
+fn is_valid_sudo_rule(rule: &str) -> bool {
+    let Some((hosts, commands)) = rule.split_once('=') else {
+        return false;
+    };
+    let hosts = hosts.trim();
+
+    !rule.contains(['\n', '\r', '#'])
+        && !rule.trim_end().ends_with('\\')
+        && !hosts.is_empty()
+        && hosts.split(',').all(|host| {
+            let host = host.trim().trim_start_matches('!');
+            !host.is_empty()
+                && host
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || "._-:/*".contains(c))
+        })
+        && !commands.trim().is_empty()
+}
+
+/**
+Check if a password is a valid crypt hash.
+A hash is either `$<id>$[<params>$]<salt>$<hash>` or a traditional DES hash (13 characters),
+it may be prefixed by `!` when locked. `*` and `!` alone are also accepted to disable the password.
+
+# Parameters
+* `hash`: The hash to check.
+ */
+pub fn is_valid_hash(hash: &str) -> bool {
+    let is_crypt_char = |c: char| c.is_ascii_alphanumeric() || c == '.' || c == '/';
+    let hash = hash.strip_prefix('!').unwrap_or(hash);
+
+    if hash.is_empty() || hash == "*" {
+        return true;
+    }
+
+    if let Some(rest) = hash.strip_prefix('$') {
+        let parts: Vec<&str> = rest.split('$').collect();
+        return parts.len() >= 3
+            && CRYPT_IDS.contains(&parts[0])
+            && parts[1..].iter().all(|p| !p.is_empty())
+            && parts[parts.len() - 1].chars().all(is_crypt_char);
+    }
+
+    hash.len() == 13 && hash.chars().all(is_crypt_char)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::s;
+
+    /**
+    Parse the local section of a sync file, the members are validated before being resolved.
+
+    # Parameters
+    * `json`: The local section, in JSON.
+     */
+    fn local_root(json: &str) -> LocalRoot {
+        serde_json::from_str(json).unwrap()
+    }
+
+    /**
+    Validate the local section of a sync file.
+
+    # Parameters
+    * `json`: The local section, in JSON.
+     */
+    fn validate_json(json: &str) -> Validation {
+        validate(&local_root(json))
+    }
+
+    /**
+    Validate a sync file with a single user expiring on a date.
+
+    # Parameters
+    * `expire`: The expiry date of the user.
+     */
+    fn validate_expire(expire: &str) -> Validation {
+        validate_json(&format!(
+            r#"{{
+                "encrypted": true,
+                "users": [{{
+                    "username": "alice",
+                    "password": "!",
+                    "groups": ["staff"],
+                    "aging": {{ "expire": "{}" }}
+                }}],
+                "groups": [{{ "name": "staff" }}]
+            }}"#,
+            expire
+        ))
+    }
+
+    #[test]
+    fn expiry_dates_are_validated() {
+        for expire in ["never", "1970-01-03", "2030-02-28", "2024-02-29"] {
+            let validation = validate_expire(expire);
+            assert!(validation.is_valid(), "{}: {:?}", expire, validation.errors);
+        }
+
+        for expire in ["2030-02-30", "2023-02-29", "30-01-2030", "2030-1-1", ""] {
+            assert_eq!(
+                validate_expire(expire).errors,
+                vec![format!(
+                    "Expiry date `{}` of user `alice` must be `YYYY-MM-DD` or `never`.",
+                    expire
+                )]
+            );
+        }
+    }
+
+    #[test]
+    fn expiry_dates_read_as_another_value_are_rejected() {
+        // Never (-1), expired (0) and disabled by the application (1).
+        for expire in ["1969-12-31", "1970-01-01", "1970-01-02", "1900-01-01"] {
+            assert_eq!(
+                validate_expire(expire).errors,
+                vec![format!(
+                    "Expiry date `{}` of user `alice` must be after 1970-01-02.",
+                    expire
+                )]
+            );
+        }
+    }
+
+    #[test]
+    fn negative_aging_values_are_rejected() {
+        let validation = validate_json(
+            r#"{
+                "encrypted": true,
+                "users": [{
+                    "username": "alice",
+                    "password": "!",
+                    "groups": ["staff"],
+                    "aging": { "minimum": -1, "maximum": 99999, "warn": -2 }
+                }],
+                "groups": [{ "name": "staff" }]
+            }"#,
+        );
+        assert_eq!(
+            validation.errors,
+            vec![s!(
+                "Aging `warn` of user `alice` must be a number of days or -1."
+            )]
+        );
+    }
+
+    #[test]
+    fn sudo_rules_are_validated() {
+        for rule in [
+            "ALL=(ALL:ALL) ALL",
+            "ALL=(ALL) NOPASSWD: /usr/bin/systemctl restart nginx",
+            " web01, !db01 = /usr/bin/journalctl ",
+            "*.example.org=ALL",
+        ] {
+            assert!(is_valid_sudo_rule(rule), "{}", rule);
+        }
+
+        for rule in [
+            "",
+            "ALL",
+            "=ALL",
+            "ALL=",
+            "ALL= ",
+            "web 01=ALL",
+            "web01,=ALL",
+            "ALL=ALL # comment",
+            "ALL=ALL\nalice ALL=ALL",
+            // A trailing backslash would join the next rule of the drop-in.
+            "ALL=/usr/bin/ls \\",
+            "ALL=/usr/bin/ls \\  ",
+        ] {
+            assert!(!is_valid_sudo_rule(rule), "{}", rule);
+        }
+    }
+
+    #[test]
+    fn valid_sync_file_has_no_error() {
+        let validation = validate_json(
+            r#"{
+                "encrypted": true,
+                "users": [{
+                    "username": "alice",
+                    "password": "$6$salt$hash",
+                    "groups": ["staff", "devs"],
+                    "gecos": "Alice",
+                    "home": "/home/alice",
+                    "shell": "/bin/bash",
+                    "ssh_keys": ["ssh-ed25519 AAAAC3NzaC1lZDI1NTE5 alice@laptop"],
+                    "sudo": ["ALL=(ALL) ALL"]
+                }],
+                "groups": [{ "name": "staff", "gid": 1000 }, { "name": "devs", "members": ["alice"] }]
+            }"#,
+        );
+        assert!(validation.is_valid(), "{:?}", validation.errors);
+        assert!(validation.warnings.is_empty(), "{:?}", validation.warnings);
+    }
+
+    #[test]
+    fn every_error_is_reported() {
+        let mut sync = local_root(
+            r#"{
+                "encrypted": true,
+                "users": [
+                    {
+                        "username": "Alice",
+                        "password": "plain",
+                        "groups": ["staff", "missing"],
+                        "gecos": "Alice:Admin",
+                        "home": "home/alice",
+                        "ssh_keys": ["ssh-foo AAAA"],
+                        "sudo": ["ALL"]
+                    },
+                    { "username": "bob", "password": "!", "groups": [] },
+                    { "username": "bob", "password": "!", "groups": ["staff"], "encrypted": false }
+                ],
+                "groups": [{ "name": "staff" }, { "name": "staff" }, { "name": "9ops", "sudo": ["=ALL"] }]
+            }"#,
+        );
+        sync.home_policy = HomePolicy {
+            mode: Some(s!("rwx")),
+            archive_dir: Some(s!("archives")),
+            ..HomePolicy::default()
+        };
+        let validation = validate(&sync);
+        assert_eq!(
+            validation.errors,
+            vec![
+                s!("Home mode `rwx` of the sync file must be an octal mode (e.g. `0750`)."),
+                s!("Archive directory of the sync file must be an absolute path."),
+                s!("Group `staff` is defined more than once."),
+                s!("Group name `9ops` is invalid."),
+                s!("Sudo rule `=ALL` of group `9ops` is invalid."),
+                s!("User `bob` is defined more than once."),
+                s!("Username `Alice` is invalid."),
+                s!("Group `missing` of user `Alice` is not defined."),
+                s!("GECOS of user `Alice` can't contain `:` or a new line."),
+                s!("Home directory of user `Alice` must be an absolute path without `:`."),
+                s!("SSH key `ssh-foo AAAA` of user `Alice` is invalid."),
+                s!("Sudo rule `ALL` of user `Alice` is invalid."),
+                s!("Password of user `Alice` is not a valid crypt hash."),
+                s!("User `bob` needs at least one group (its primary group)."),
+            ]
+        );
+    }
+
+    #[test]
+    fn duplicated_ids_and_members_are_warnings() {
+        let validation = validate_json(
+            r#"{
+                "encrypted": true,
+                "users": [
+                    { "username": "alice", "uid": 1000, "password": "!", "groups": ["staff"] },
+                    { "username": "bob", "uid": 1000, "password": "!", "groups": ["staff"] }
+                ],
+                "groups": [
+                    { "name": "staff", "gid": 1000, "members": ["alice", "alice"] },
+                    { "name": "devs", "gid": 1000 }
+                ]
+            }"#,
+        );
+        assert!(validation.is_valid(), "{:?}", validation.errors);
+        assert_eq!(
+            validation.warnings,
+            vec![
+                s!("Member `alice` is listed more than once in group `staff`."),
+                s!("GID 1000 is forced on more than one group."),
+                s!("UID 1000 is forced on more than one user."),
+            ]
+        );
+    }
+
+    #[test]
+    fn names_are_validated() {
+        for name in ["alice", "_apt", "web-01", "host$", "a_b-c9"] {
+            assert!(is_valid_name(name), "{}", name);
+        }
+        for name in [
+            "",
+            "Alice",
+            "9lives",
+            "-alice",
+            "al ice",
+            "al$ce",
+            "$",
+            &"a".repeat(33),
+        ] {
+            assert!(!is_valid_name(name), "{}", name);
+        }
+    }
+
+    #[test]
+    fn hashes_are_validated() {
+        for hash in [
+            "",
+            "*",
+            "!",
+            "!$6$salt$hash",
+            "$6$rounds=5000$salt$hash",
+            "$y$j9T$salt$hash",
+            "abcdefghij./0",
+        ] {
+            assert!(is_valid_hash(hash), "{}", hash);
+        }
+        for hash in [
+            "plain",
+            "$6$salt",
+            "$9$salt$hash",
+            "$6$$hash",
+            "$6$salt$ha:sh",
+            "abcdefghij./",
+        ] {
+            assert!(!is_valid_hash(hash), "{}", hash);
+        }
+    }
+}
@@ -0,0 +1,323 @@
+use std::{
+    ffi::CString,
+    fs::{self, File},
+    io::{self, Read},
+    os::unix::{
+        ffi::OsStrExt,
+        fs::MetadataExt,
+        io::{AsRawFd, FromRawFd},
+    },
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+use crate::{date, error::Error};
+
+/// The delay without any event before the changes of the sync file are synchronized,
+/// so a file written in several steps (or several files of a deployment) is only synchronized once.
+const DEBOUNCE: Duration = Duration::from_secs(2);
+
+/// The size of the header of an inotify event, followed by the name of the file.
+const EVENT_HEADER_SIZE: usize = 16;
+
+/**
+The reason a synchronization is started by the `watch` command.
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Trigger {
+    /// The sync file was created, modified, replaced or removed.
+    Changed,
+    /// The periodic interval elapsed.
+    Interval,
+}
+
+/**
+Watch the directory of the sync file with inotify.
+
+The directory is watched rather than the file itself so the file can be replaced
+(editors, configuration management or a symbolic link switched by a deployment)
+without losing the watch.
+When the sync file is a symbolic link, the directory of its target is also watched
+so the target can be modified in place.
+ */
+struct Watcher {
+    /// The inotify instance, closed when dropped.
+    inotify: File,
+    /// The watch descriptor of the directory of the sync file.
+    dir_watch: i32,
+    /// The sync file.
+    path: PathBuf,
+    /// The metadata of the sync file when it was last synchronized.
+    fingerprint: Option<Fingerprint>,
+}
+
+/**
+The metadata identifying a version of the sync file.
+The link is followed so the replacement of the target of a symbolic link is a change.
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Fingerprint {
+    dev: u64,
+    ino: u64,
+    size: u64,
+    mtime: i64,
+    mtime_nsec: i64,
+}
+
+impl Fingerprint {
+    /**
+    Get the fingerprint of a file.
+
+    # Parameters
+    * `path`: The path of the file.
+
+    # Returns
+    The fingerprint, `None` when the file doesn't exist.
+     */
+    fn of(path: &Path) -> Option<Self> {
+        fs::metadata(path).ok().map(|m| Self {
+            dev: m.dev(),
+            ino: m.ino(),
+            size: m.size(),
+            mtime: m.mtime(),
+            mtime_nsec: m.mtime_nsec(),
+        })
+    }
+}
+
+impl Watcher {
+    /**
+    Start watching a sync file.
+
+    # Parameters
+    * `path`: The path of the sync file, its directory must exist.
+     */
+    fn new(path: &Path) -> io::Result<Self> {
+        // SAFETY: `inotify_init1` only takes flags, the descriptor is checked before use.
+        let fd = unsafe { libc::inotify_init1(libc::IN_CLOEXEC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: The descriptor was just created and isn't owned by anything else,
+        // the file now owns it and closes it on error.
+        let inotify = unsafe { File::from_raw_fd(fd) };
+
+        let mut watcher = Self {
+            inotify,
+            dir_watch: -1,
+            path: path.to_path_buf(),
+            fingerprint: Fingerprint::of(path),
+        };
+        watcher.dir_watch = watcher.add_watch(parent_dir(path))?;
+        watcher.watch_target()?;
+
+        Ok(watcher)
+    }
+
+    /**
+    Watch the changes of the files of a directory.
+
+    # Parameters
+    * `dir`: The directory to watch.
+
+    # Returns
+    The watch descriptor, the same one when the directory is already watched.
+     */
+    fn add_watch(&self, dir: &Path) -> io::Result<i32> {
+        let dir_name = CString::new(dir.as_os_str().as_bytes())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        let mask = libc::IN_CLOSE_WRITE
+            | libc::IN_CREATE
+            | libc::IN_DELETE
+            | libc::IN_MOVED_FROM
+            | libc::IN_MOVED_TO
+            | libc::IN_ATTRIB;
+
+        // SAFETY: The inotify descriptor is open as long as `self`,
+        // the name is a valid NUL-terminated string living until the end of the call.
+        let watch =
+            unsafe { libc::inotify_add_watch(self.inotify.as_raw_fd(), dir_name.as_ptr(), mask) };
+        match watch < 0 {
+            true => Err(io::Error::last_os_error()),
+            false => Ok(watch),
+        }
+    }
+
+    /**
+    Watch the directory of the target of the sync file, when it is a symbolic link.
+    A missing or dangling sync file is only followed through its own directory.
+     */
+    fn watch_target(&self) -> io::Result<()> {
+        let target = match fs::canonicalize(&self.path) {
+            Ok(target) => target,
+            Err(_) => return Ok(()),
+        };
+        let target_dir = parent_dir(&target);
+        let dir = fs::canonicalize(parent_dir(&self.path))?;
+        if target_dir != dir {
+            self.add_watch(target_dir)?;
+        }
+
+        Ok(())
+    }
+
+    /**
+    Wait until the sync file changes or the interval elapses.
+    The events of the other files of the directory are ignored.
+
+    # Parameters
+    * `interval`: The maximum time to wait, `None` to only wait for a change.
+
+    # Returns
+    The reason to synchronize again.
+     */
+    fn wait(&mut self, interval: Option<Duration>) -> io::Result<Trigger> {
+        let deadline = interval.map(|i| Instant::now() + i);
+        loop {
+            let timeout = deadline.map(|d| d.saturating_duration_since(Instant::now()));
+            if timeout == Some(Duration::ZERO) {
+                return Ok(Trigger::Interval);
+            }
+            if !self.read_events(timeout)? {
+                continue;
+            }
+
+            let fingerprint = Fingerprint::of(&self.path);
+            if fingerprint == self.fingerprint {
+                continue;
+            }
+
+            // Let the writes settle before reading the file.
+            while self.read_events(Some(DEBOUNCE))? {}
+            self.fingerprint = Fingerprint::of(&self.path);
+            // The link may point to another directory now.
+            self.watch_target()?;
+            return Ok(Trigger::Changed);
+        }
+    }
+
+    /**
+    Wait for the next inotify events and drain them.
+
+    # Parameters
+    * `timeout`: The maximum time to wait, `None` to wait indefinitely.
+
+    # Returns
+    `true` when some events were read, `false` when the timeout elapsed or the wait was interrupted.
+     */
+    fn read_events(&mut self, timeout: Option<Duration>) -> io::Result<bool> {
+        let mut poll = libc::pollfd {
+            fd: self.inotify.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        let timeout = timeout.map_or(-1, |t| t.as_millis().min(i32::MAX as u128) as i32);
+        // SAFETY: `poll` points to a single valid `pollfd`, matching the count of 1.
+        match unsafe { libc::poll(&mut poll, 1, timeout) } {
+            0 => return Ok(false),
+            n if n < 0 => {
+                let e = io::Error::last_os_error();
+                return match e.kind() {
+                    io::ErrorKind::Interrupted => Ok(false),
+                    _ => Err(e),
+                };
+            }
+            _ => {}
+        }
+
+        let mut buffer = [0u8; 4096];
+        let size = self.inotify.read(&mut buffer)?;
+        let mut offset = 0;
+        while offset + EVENT_HEADER_SIZE <= size {
+            let field = |index: usize| {
+                let start = offset + index * 4;
+                u32::from_ne_bytes([
+                    buffer[start],
+                    buffer[start + 1],
+                    buffer[start + 2],
+                    buffer[start + 3],
+                ])
+            };
+            // The watch is removed with the directory, nothing would be reported anymore.
+            // The directory of a previous target of the link can be removed.
+            if field(0) as i32 == self.dir_watch && field(1) & libc::IN_IGNORED != 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    "the directory of the sync file was removed",
+                ));
+            }
+            offset += EVENT_HEADER_SIZE + field(3) as usize;
+        }
+
+        Ok(true)
+    }
+}
+
+/**
+Get the directory of a file.
+
+# Parameters
+* `path`: The path of the file.
+
+# Returns
+The parent directory, `.` for a relative path without directory.
+ */
+fn parent_dir(path: &Path) -> &Path {
+    match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    }
+}
+
+/**
+Synchronize, then synchronize again every time the sync file changes or the interval elapses.
+The failed synchronizations are printed and the next change is waited for,
+so an invalid sync file can be fixed without restarting the command.
+
+# Parameters
+* `path`: The path of the sync file.
+* `interval`: The delay between two synchronizations even when the sync file didn't change.
+* `sync`: The synchronization.
+
+# Returns
+Only returns the error preventing the sync file from being watched.
+ */
+pub fn watch<F>(path: &Path, interval: Option<Duration>, mut sync: F) -> Result<(), Error>
+where
+    F: FnMut() -> Result<(), Error>,
+{
+    let mut watcher = Watcher::new(path).map_err(Error::Watch)?;
+    match interval {
+        Some(interval) => log(&format!(
+            "Watching {} (synchronizing at least every {}s).",
+            path.display(),
+            interval.as_secs()
+        )),
+        None => log(&format!("Watching {}.", path.display())),
+    }
+
+    loop {
+        if let Err(e) = sync() {
+            log(&format!(
+                "Synchronization failed (exit code {}).",
+                e.exit_code()
+            ));
+            println!("{}", e);
+        }
+
+        match watcher.wait(interval).map_err(Error::Watch)? {
+            Trigger::Changed => log("The sync file changed, synchronizing..."),
+            Trigger::Interval => log("Periodic synchronization..."),
+        }
+    }
+}
+
+/**
+Print a message of the `watch` command with the current date and time.
+
+# Parameters
+* `message`: The message to print.
+ */
+fn log(message: &str) {
+    println!("[{}] {}", date::now(), message);
+}